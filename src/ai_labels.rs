@@ -1,8 +1,7 @@
 use anyhow::{Context, Result};
-use log::error;
-use sonogram::Spectrogram;
 use std::io::{Read, Write};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{fs, thread};
 
 use std::{
@@ -15,8 +14,13 @@ use serde::{Deserialize, Serialize};
 
 use crate::file::{EntryKind, list_dir, list_dir_all, relative_path_from_base};
 use crate::mkv::process_mkv_file;
-use crate::sound::S_SPECTROGRAM_NUM_BINS;
-use crate::spectrogram::{generate_spectrogram, save_spectrogram};
+use crate::progress::{Amount, JobControl, Progress, Stage};
+use crate::sound::loudness::{self, LoudnessStats};
+use crate::sound::{DecodeOptions, S_SPECTROGRAM_NUM_BINS, TimeRange};
+use crate::spectrogram::{
+    ZaoSpectrogram, generate_spectrogram, generate_spectrogram_with_config, save_spectrogram,
+};
+use crate::stft::SpectrogramConfig;
 use crate::{chapters::VideoMetadata, utils::ListDirSplit};
 
 pub const ZAOAI_LABEL_VERSION: u8 = 1;
@@ -35,6 +39,42 @@ pub struct ZaoaiLabel {
     pub opening_end_frame: Option<u32>,
     pub opening_start_normalized: Option<f64>,
     pub opening_end_normalized: Option<f64>,
+
+    /// Set when the label describes a decoded window of the file instead of
+    /// the whole of it. Times stay absolute, the normalized values are
+    /// relative to the window.
+    #[serde(default)]
+    pub window: Option<TimeRange>,
+
+    /// Loudness of the decoded audio, see [`ZaoaiLabel::measure_loudness`].
+    #[serde(default)]
+    pub loudness: Option<LoudnessStats>,
+
+    /// Where the opening times come from.
+    #[serde(default)]
+    pub provenance: LabelProvenance,
+}
+
+/// The labeled video file.
+impl AsRef<Path> for ZaoaiLabel {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub enum LabelProvenance {
+    /// Read from the file's chapters.
+    #[default]
+    Chapters,
+    /// Inferred by aligning the opening of a labeled sibling episode, see
+    /// [`crate::propagate`].
+    Propagated {
+        /// Video file of the label the opening was aligned from.
+        from: PathBuf,
+        /// Alignment confidence in `[0, 1]`.
+        confidence: f32,
+    },
 }
 
 impl ZaoaiLabel {
@@ -42,6 +82,32 @@ impl ZaoaiLabel {
         self.opening_start_frame.is_some() && self.opening_end_frame.is_some()
     }
 
+    /// Returns a copy of the label re-normalized against `window`, typically
+    /// the offset and duration of a [`crate::spectrogram::SpectrogramWindow`].
+    pub fn windowed(&self, window: TimeRange) -> ZaoaiLabel {
+        let window_end = window.end.unwrap_or(self.metadata.duration);
+        let window_secs = window_end.saturating_sub(window.start).as_secs_f64();
+        let normalize = |t: Option<Duration>| {
+            t.filter(|_| window_secs > 0.0)
+                .map(|t| (t.as_secs_f64() - window.start.as_secs_f64()) / window_secs)
+        };
+
+        ZaoaiLabel {
+            opening_start_normalized: normalize(self.opening_start_time),
+            opening_end_normalized: normalize(self.opening_end_time),
+            window: Some(TimeRange::new(window.start, window_end)),
+            ..self.clone()
+        }
+    }
+
+    /// Measures the loudness of the labeled file and stores it on the label.
+    pub fn measure_loudness(&mut self) -> Result<LoudnessStats> {
+        let stats = loudness::measure_file(&self.path, &DecodeOptions::default())
+            .with_context(|| format!("Failed to measure loudness of {}", self.path.display()))?;
+        self.loudness = Some(stats);
+        Ok(stats)
+    }
+
     pub fn expected_outputs(&self) -> Vec<f32> {
         let mut start_normalized = None;
         let mut end_normalized = None;
@@ -59,6 +125,30 @@ impl ZaoaiLabel {
     }
 }
 
+/// Labels whose integrated loudness is more than `max_deviation_lu` away
+/// from the median of all measured labels. Unmeasured labels are ignored.
+pub fn loudness_outliers(labels: &[ZaoaiLabel], max_deviation_lu: f64) -> Vec<&ZaoaiLabel> {
+    let mut measured: Vec<f64> = labels
+        .iter()
+        .filter_map(|l| l.loudness?.integrated_lufs)
+        .collect();
+    if measured.is_empty() {
+        return Vec::new();
+    }
+    measured.sort_by(f64::total_cmp);
+    let median = measured[measured.len() / 2];
+
+    labels
+        .iter()
+        .filter(|l| {
+            l.loudness.is_some_and(|s| {
+                s.integrated_lufs
+                    .is_none_or(|lufs| (lufs - median).abs() > max_deviation_lu)
+            })
+        })
+        .collect()
+}
+
 pub fn collect_zaoai_labels(
     list_dir_split: &ListDirSplit,
     out_path: impl AsRef<Path>,
@@ -71,7 +161,7 @@ pub fn collect_zaoai_labels(
         let path_buf = entry_with_chapters.as_ref();
         let zaoai_label = if path_buf.is_file() {
             if path_buf.is_file() {
-                let b = process_mkv_file(&entry_with_chapters);
+                let b = process_mkv_file(entry_with_chapters);
                 match b {
                     Ok(mkv_metadata) => {
                         let (Some(op_start), Some(op_end)) = mkv_metadata.extract_opening_times()
@@ -154,74 +244,42 @@ pub fn collect_zaoai_labels_multithread(
     list_dir_split: &ListDirSplit,
     out_path: impl AsRef<Path>,
 ) -> Result<()> {
-    let out_path = out_path.as_ref().to_path_buf(); // clone for thread move
-    let path_source = list_dir_split.path_source.clone();
+    collect_zaoai_labels_multithread_with_control(list_dir_split, out_path, &JobControl::default())
+}
+
+/// [`collect_zaoai_labels_multithread`] reporting finished entries to
+/// `control`. Once cancelled, entries not yet started are skipped and
+/// [`crate::progress::Cancelled`] is returned.
+pub fn collect_zaoai_labels_multithread_with_control(
+    list_dir_split: &ListDirSplit,
+    out_path: impl AsRef<Path>,
+    control: &JobControl,
+) -> Result<()> {
+    let out_path = out_path.as_ref();
+    let path_source = list_dir_split.path_source.as_path();
+    let total = list_dir_split.with_chapters.len() as u64;
+    let done = AtomicU64::new(0);
 
     std::thread::scope(|scope| {
         let mut handles = vec![];
 
-        for entry in list_dir_split.with_chapters.iter().cloned() {
-            let path_source = path_source.clone();
-            let out_path = out_path.clone();
+        for entry in &list_dir_split.with_chapters {
+            let done = &done;
 
             let handle = scope.spawn(move || -> Result<(), anyhow::Error> {
                 let path_buf = entry.as_ref();
-
-                if !path_buf.is_file() {
-                    return Ok(()); // skip non-files
-                }
-
-                let mkv_metadata = match process_mkv_file(&entry) {
-                    Ok(m) => m,
-                    Err(e) => {
-                        eprintln!("process_mkv_file error: {e}");
-                        return Ok(());
-                    }
-                };
-
-                let (Some(op_start), Some(op_end)) = mkv_metadata.extract_opening_times() else {
-                    // Same effect as if zaoai_label was None
+                if control.is_cancelled() {
                     return Ok(());
-                };
-
-                let video_metadata: VideoMetadata = mkv_metadata.into();
-                let total_secs = video_metadata.duration.as_secs_f64();
-
-                let label = ZaoaiLabel {
-                    path: path_buf.to_path_buf(),
-                    path_source: path_source.clone(),
-                    metadata: video_metadata,
-                    version: ZAOAI_LABEL_VERSION,
-                    opening_start_time: Some(op_start),
-                    opening_end_time: Some(op_end),
-                    opening_start_frame: None,
-                    opening_end_frame: None,
-                    opening_start_normalized: Some(op_start.as_secs_f64() / total_secs),
-                    opening_end_normalized: Some(op_end.as_secs_f64() / total_secs),
-                    ..Default::default()
-                };
-
-                let relative_path = relative_path_from_base(path_buf, &label.path_source)
-                    .with_context(|| format!("Failed to compute relative path"))?;
-                let output_path = out_path.join(relative_path).with_extension("zlbl");
-
-                if let Some(parent) = output_path.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-
-                if output_path.exists() {
-                    eprintln!(
-                        "Warning: Output file already exists: {}",
-                        output_path.display()
-                    );
                 }
 
-                let mut file = File::create(&output_path)?;
-                let json = serde_json::to_string_pretty(&label)?;
-                writeln!(file, "{}", json)?;
-
-                println!("Wrote: {}", output_path.display());
-                Ok(())
+                let result = write_label_for_entry(entry, path_source, out_path);
+                control.report(Progress {
+                    stage: Stage::Labeling,
+                    file: Some(path_buf),
+                    processed: Amount::Items(done.fetch_add(1, Ordering::Relaxed) + 1),
+                    total: Some(Amount::Items(total)),
+                });
+                result
             });
 
             handles.push(handle);
@@ -236,9 +294,76 @@ pub fn collect_zaoai_labels_multithread(
         Ok::<(), anyhow::Error>(())
     })?;
 
+    control.check()?;
     Ok(())
 }
 
+fn write_label_for_entry(entry: &EntryKind, path_source: &Path, out_path: &Path) -> Result<()> {
+    let path_buf = entry.as_ref();
+
+    if !path_buf.is_file() {
+        return Ok(()); // skip non-files
+    }
+
+    let mkv_metadata = match process_mkv_file(entry) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("process_mkv_file error: {e}");
+            return Ok(());
+        }
+    };
+
+    let (Some(op_start), Some(op_end)) = mkv_metadata.extract_opening_times() else {
+        // Same effect as if zaoai_label was None
+        return Ok(());
+    };
+
+    let video_metadata: VideoMetadata = mkv_metadata.into();
+    let total_secs = video_metadata.duration.as_secs_f64();
+
+    let label = ZaoaiLabel {
+        path: path_buf.to_path_buf(),
+        path_source: path_source.to_path_buf(),
+        metadata: video_metadata,
+        version: ZAOAI_LABEL_VERSION,
+        opening_start_time: Some(op_start),
+        opening_end_time: Some(op_end),
+        opening_start_frame: None,
+        opening_end_frame: None,
+        opening_start_normalized: Some(op_start.as_secs_f64() / total_secs),
+        opening_end_normalized: Some(op_end.as_secs_f64() / total_secs),
+        ..Default::default()
+    };
+
+    write_label(&label, out_path)?;
+    Ok(())
+}
+
+/// Writes `label` to `out_path`, mirroring its location below `path_source`.
+pub(crate) fn write_label(label: &ZaoaiLabel, out_path: &Path) -> Result<PathBuf> {
+    let relative_path = relative_path_from_base(&label.path, &label.path_source)
+        .context("Failed to compute relative path")?;
+    let output_path = out_path.join(relative_path).with_extension("zlbl");
+
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if output_path.exists() {
+        eprintln!(
+            "Warning: Output file already exists: {}",
+            output_path.display()
+        );
+    }
+
+    let mut file = File::create(&output_path)?;
+    let json = serde_json::to_string_pretty(label)?;
+    writeln!(file, "{}", json)?;
+
+    println!("Wrote: {}", output_path.display());
+    Ok(output_path)
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ZaoaiLabelsLoader {
     pub path_source: PathBuf,
@@ -248,8 +373,13 @@ pub struct ZaoaiLabelsLoader {
 
 impl ZaoaiLabelsLoader {
     pub fn load_single(path: impl AsRef<Path>) -> Result<ZaoaiLabel> {
-        assert_eq!(path.as_ref().is_file(), true);
-        assert_eq!(path.as_ref().extension().unwrap(), "zlbl");
+        let path = path.as_ref();
+        anyhow::ensure!(path.is_file(), "{} is not a file", path.display());
+        anyhow::ensure!(
+            path.extension().is_some_and(|e| e == "zlbl"),
+            "{} is not a .zlbl label",
+            path.display()
+        );
 
         let label = Self::load_zaoai_label(path)?;
         Ok(label)
@@ -292,6 +422,8 @@ impl ZaoaiLabelsLoader {
     }
 }
 
+/// Files that fail are logged and skipped, the others are still written.
+/// The returned error lists every failed path.
 pub fn generate_zaoai_label_spectrograms(
     list: &Vec<EntryKind>,
     spectrogram_file_extension: &String,
@@ -308,7 +440,7 @@ pub fn generate_zaoai_label_spectrograms(
         match entry {
             EntryKind::File(path_buf) => {
                 if path_buf.extension().unwrap() == "zlbl" {
-                    assert_eq!(path_buf.is_file(), true);
+                    assert!(path_buf.is_file());
 
                     // Load zaoai_label
                     let zaoai_label = ZaoaiLabelsLoader::load_single(path_buf)?;
@@ -326,6 +458,7 @@ pub fn generate_zaoai_label_spectrograms(
                                 &specto,
                                 spectrogram_dim[0],
                                 spectrogram_dim[1],
+                                Some(&zaoai_label.path),
                                 &spectrogram_save_path,
                             )?;
 
@@ -345,7 +478,7 @@ pub fn generate_zaoai_label_spectrograms(
                 let dir_list_dir = list_dir(path_buf, true)?;
                 generate_zaoai_label_spectrograms(
                     &dir_list_dir,
-                    &spectrogram_file_extension,
+                    spectrogram_file_extension,
                     spectrogram_dim,
                 )?;
             }
@@ -358,12 +491,41 @@ pub fn generate_zaoai_label_spectrograms(
     Ok(())
 }
 
+/// Files that fail are logged and skipped, the others are still written.
+/// The returned error lists every failed path.
 pub fn generate_zaoai_label_spectrograms_multithread(
     list: &[EntryKind],
-    spectrogram_file_extension: &String,
+    spectrogram_file_extension: &str,
+    spectrogram_dim: [usize; 2],
+) -> Result<()> {
+    generate_label_spectrograms(list, spectrogram_file_extension, spectrogram_dim, None)
+}
+
+/// Like [`generate_zaoai_label_spectrograms_multithread`] but computed by
+/// the in-crate STFT, e.g. for mel or constant-Q spectrograms. `config` is
+/// stored in every written file. Failures are reported the same way.
+pub fn generate_zaoai_label_spectrograms_with_config(
+    list: &[EntryKind],
+    spectrogram_file_extension: &str,
     spectrogram_dim: [usize; 2],
+    config: &SpectrogramConfig,
 ) -> Result<()> {
-    let extension_arc = Arc::new(spectrogram_file_extension.clone());
+    generate_label_spectrograms(
+        list,
+        spectrogram_file_extension,
+        spectrogram_dim,
+        Some(config),
+    )
+}
+
+/// sonogram spectrograms when `config` is `None`.
+fn generate_label_spectrograms(
+    list: &[EntryKind],
+    spectrogram_file_extension: &str,
+    spectrogram_dim: [usize; 2],
+    config: Option<&SpectrogramConfig>,
+) -> Result<()> {
+    let extension_arc = Arc::new(spectrogram_file_extension.to_owned());
 
     let mut count = 0;
 
@@ -382,47 +544,36 @@ pub fn generate_zaoai_label_spectrograms_multithread(
                     let path_buf = path_buf.clone();
                     let dim = spectrogram_dim;
 
-                    let handle = scope.spawn(move || {
-                        if path_buf.extension().unwrap_or_default() == "zlbl" && path_buf.is_file()
+                    let handle = scope.spawn(move || -> Result<()> {
+                        if path_buf.extension().unwrap_or_default() != "zlbl" || !path_buf.is_file()
                         {
-                            match ZaoaiLabelsLoader::load_single(&path_buf) {
-                                Ok(zaoai_label) => {
-                                    match generate_spectrogram(
-                                        &zaoai_label.path,
-                                        S_SPECTROGRAM_NUM_BINS,
-                                    ) {
-                                        Ok(specto) => {
-                                            let mut save_path = path_buf.clone();
-                                            let success = save_path
-                                                .set_extension(&*spectrogram_file_extension);
-                                            assert!(success);
-                                            save_spectrogram(&specto, dim[0], dim[1], &save_path)?;
-                                            log::info!(
-                                                "Saved spectrogram: {}",
-                                                save_path.display()
-                                            );
-                                        }
-                                        Err(e) => {
-                                            log::error!(
-                                                "Spectrogram error on file:\n{}\nError: {:?}",
-                                                zaoai_label.path.display(),
-                                                e
-                                            );
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    log::error!(
-                                        "Label load error on file:\n{}\nError: {:?}",
-                                        path_buf.display(),
-                                        e
-                                    );
-                                }
-                            }
+                            return Ok(());
+                        }
+                        let zaoai_label = ZaoaiLabelsLoader::load_single(&path_buf)?;
+                        let specto = match config {
+                            Some(config) => generate_spectrogram_with_config(
+                                &zaoai_label.path,
+                                config,
+                                &JobControl::default(),
+                            ),
+                            None => generate_spectrogram(&zaoai_label.path, S_SPECTROGRAM_NUM_BINS),
                         }
-                        Ok::<(), anyhow::Error>(())
+                        .with_context(|| format!("{}", zaoai_label.path.display()))?;
+
+                        let mut save_path = path_buf.clone();
+                        let success = save_path.set_extension(&*spectrogram_file_extension);
+                        assert!(success);
+                        save_spectrogram(
+                            &specto,
+                            dim[0],
+                            dim[1],
+                            Some(&zaoai_label.path),
+                            &save_path,
+                        )?;
+                        log::info!("Saved spectrogram: {}", save_path.display());
+                        Ok(())
                     });
-                    handles.push(handle);
+                    handles.push((entry.as_ref().to_path_buf(), handle));
                 }
 
                 EntryKind::Directory(path_buf) => {
@@ -431,13 +582,14 @@ pub fn generate_zaoai_label_spectrograms_multithread(
 
                     let handle = scope.spawn(move || {
                         let dir_list = list_dir(&path_buf, true)?;
-                        generate_zaoai_label_spectrograms_multithread(
+                        generate_label_spectrograms(
                             &dir_list,
                             &spectrogram_file_extension,
                             dim,
+                            config,
                         )
                     });
-                    handles.push(handle);
+                    handles.push((entry.as_ref().to_path_buf(), handle));
                 }
 
                 EntryKind::Other(_) => {
@@ -446,16 +598,29 @@ pub fn generate_zaoai_label_spectrograms_multithread(
             }
         }
 
-        for handle in handles {
-            handle.join().unwrap()?;
+        let mut failed = Vec::new();
+        for (path, handle) in handles {
+            let error = match handle.join() {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => format!("{e:#}"),
+                Err(_) => "worker panicked".to_string(),
+            };
+            log::error!("Spectrogram failed for {}: {error}", path.display());
+            failed.push(format!("{}: {error}", path.display()));
         }
 
+        anyhow::ensure!(
+            failed.is_empty(),
+            "{} spectrogram job(s) failed:\n{}",
+            failed.len(),
+            failed.join("\n")
+        );
         Ok(())
     })
 }
 
 pub struct AnimeDataPoint {
     pub path: PathBuf,
-    pub spectrogram: Spectrogram,
+    pub spectrogram: ZaoSpectrogram,
     pub expected_outputs: Vec<f32>,
 }
@@ -0,0 +1,476 @@
+//! Seeded data augmentation of spectrograms and decoded audio.
+//!
+//! Every transform that moves audio in time returns the label adjusted to
+//! match. Adjusted labels are relative to the augmented data: the window
+//! is cleared, times count from its first column or sample and the
+//! metadata duration is its length. An opening moved entirely out of the
+//! data is removed from the label.
+//!
+//! Randomness comes from a seeded [`fastrand::Rng`], so an [`Augmenter`]
+//! with the same seed and config produces the same examples.
+
+use std::ops::Range;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::ai_labels::ZaoaiLabel;
+use crate::dataset::{FrameTargets, TrainingWindow};
+use crate::sound::DecodedAudio;
+use crate::sound::resample::resample;
+use crate::spectrogram::render::resample as resample_grid;
+use crate::spectrogram::{SpectrogramValues, ZaoSpectrogram};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AugmentConfig {
+    /// Number of SpecAugment time masks and their maximum width in columns.
+    pub time_masks: usize,
+    pub max_time_mask: usize,
+    /// Number of frequency masks and their maximum height in rows.
+    pub frequency_masks: usize,
+    pub max_frequency_mask: usize,
+    /// Value of masked cells, `None` for the spectrogram's mean.
+    pub mask_value: Option<f32>,
+    /// Maximum shift in seconds, either direction.
+    pub max_shift: f64,
+    /// Fraction of the length kept by a random crop, `None` disables cropping.
+    pub crop_fraction: Option<(f64, f64)>,
+    /// Gain range in dB.
+    pub gain_db: (f32, f32),
+    /// Noise: standard deviation in dB for spectrograms, signal-to-noise
+    /// range in dB for audio. `None` disables it.
+    pub noise_db: Option<f32>,
+    pub noise_snr_db: Option<(f32, f32)>,
+    /// Playback rate range, above 1 shortens.
+    pub stretch: Option<(f64, f64)>,
+    /// Range of the weight of the first example in [`Augmenter::mixup`].
+    pub mixup_weight: (f32, f32),
+}
+
+impl Default for AugmentConfig {
+    fn default() -> Self {
+        Self {
+            time_masks: 2,
+            max_time_mask: 20,
+            frequency_masks: 2,
+            max_frequency_mask: 15,
+            mask_value: None,
+            max_shift: 5.0,
+            crop_fraction: None,
+            gain_db: (-6.0, 6.0),
+            noise_db: Some(1.0),
+            noise_snr_db: Some((20.0, 40.0)),
+            stretch: Some((0.9, 1.1)),
+            mixup_weight: (0.6, 1.0),
+        }
+    }
+}
+
+/// Applies an [`AugmentConfig`] with a seeded generator.
+pub struct Augmenter {
+    pub config: AugmentConfig,
+    rng: fastrand::Rng,
+}
+
+impl Augmenter {
+    pub fn new(config: AugmentConfig, seed: u64) -> Self {
+        Self {
+            config,
+            rng: fastrand::Rng::with_seed(seed),
+        }
+    }
+
+    /// Stretch, crop, shift, gain, noise and masks, in that order.
+    pub fn augment(
+        &mut self,
+        spectrogram: &ZaoSpectrogram,
+        label: &ZaoaiLabel,
+    ) -> Result<(ZaoSpectrogram, ZaoaiLabel)> {
+        anyhow::ensure!(
+            spectrogram.width() > 0,
+            "cannot augment an empty spectrogram"
+        );
+        let config = self.config.clone();
+        let (mut spectrogram, mut label) = match config.stretch {
+            Some(range) => time_stretch(spectrogram, label, self.uniform(range))?,
+            None => (spectrogram.clone(), label.clone()),
+        };
+        if let Some(range) = config.crop_fraction {
+            let width = spectrogram.width();
+            let len = ((width as f64 * self.uniform(range)).round() as usize).clamp(1, width);
+            let start = self.rng.usize(0..=width - len);
+            (spectrogram, label) = crop(&spectrogram, &label, start..start + len)?;
+        }
+        if config.max_shift > 0.0 {
+            let seconds_per_column = spectrogram
+                .seconds_per_column()
+                .context("spectrogram has no sample rate or hop")?;
+            let seconds = self.uniform((-config.max_shift, config.max_shift));
+            let columns = (seconds / seconds_per_column).round() as isize;
+            (spectrogram, label) = time_shift(&spectrogram, &label, columns)?;
+        }
+        gain(&mut spectrogram, self.uniform(config.gain_db));
+        if let Some(std) = config.noise_db {
+            add_noise(&mut spectrogram, &mut self.rng, std);
+        }
+        time_masks(
+            &mut spectrogram,
+            &mut self.rng,
+            config.time_masks,
+            config.max_time_mask,
+            config.mask_value,
+        );
+        frequency_masks(
+            &mut spectrogram,
+            &mut self.rng,
+            config.frequency_masks,
+            config.max_frequency_mask,
+            config.mask_value,
+        );
+        Ok((spectrogram, label))
+    }
+
+    /// Stretch, shift, gain and noise on decoded audio.
+    pub fn augment_audio(
+        &mut self,
+        audio: &DecodedAudio,
+        label: &ZaoaiLabel,
+    ) -> Result<(DecodedAudio, ZaoaiLabel)> {
+        anyhow::ensure!(!audio.samples.is_empty(), "cannot augment empty audio");
+        let config = self.config.clone();
+        let (mut audio, mut label) = match config.stretch {
+            Some(range) => audio_time_stretch(audio, label, self.uniform(range)),
+            None => (
+                DecodedAudio {
+                    samples: audio.samples.clone(),
+                    sample_rate: audio.sample_rate,
+                    ..Default::default()
+                },
+                relabel(label, audio.duration(), |t| t),
+            ),
+        };
+        if config.max_shift > 0.0 {
+            let seconds = self.uniform((-config.max_shift, config.max_shift));
+            label = audio_shift(&mut audio, &label, seconds);
+        }
+        audio_gain(&mut audio.samples, self.uniform(config.gain_db));
+        if let Some(range) = config.noise_snr_db {
+            let snr_db = self.uniform(range);
+            audio_noise(&mut audio.samples, &mut self.rng, snr_db);
+        }
+        Ok((audio, label))
+    }
+
+    /// Mixes two windows with a random weight from [`AugmentConfig::mixup_weight`].
+    pub fn mixup(&mut self, a: &TrainingWindow, b: &TrainingWindow) -> Result<TrainingWindow> {
+        let weight = self.uniform(self.config.mixup_weight);
+        mixup(a, b, weight)
+    }
+
+    fn uniform<T: Uniform>(&mut self, (lo, hi): (T, T)) -> T {
+        T::lerp(lo, hi, self.rng.f64())
+    }
+}
+
+trait Uniform: Copy {
+    fn lerp(lo: Self, hi: Self, t: f64) -> Self;
+}
+
+impl Uniform for f32 {
+    fn lerp(lo: Self, hi: Self, t: f64) -> Self {
+        lo + (hi - lo) * t as f32
+    }
+}
+
+impl Uniform for f64 {
+    fn lerp(lo: Self, hi: Self, t: f64) -> Self {
+        lo + (hi - lo) * t
+    }
+}
+
+/// SpecAugment time masks of up to `max_width` columns.
+pub fn time_masks(
+    spectrogram: &mut ZaoSpectrogram,
+    rng: &mut fastrand::Rng,
+    count: usize,
+    max_width: usize,
+    value: Option<f32>,
+) {
+    let value = value.unwrap_or_else(|| mean(spectrogram));
+    let (width, height) = spectrogram.shape();
+    for _ in 0..count {
+        let len = rng.usize(0..=max_width.min(width));
+        let start = rng.usize(0..=width - len);
+        for row in 0..height {
+            for column in start..start + len {
+                spectrogram[(row, column)] = value;
+            }
+        }
+    }
+}
+
+/// SpecAugment frequency masks of up to `max_height` rows.
+pub fn frequency_masks(
+    spectrogram: &mut ZaoSpectrogram,
+    rng: &mut fastrand::Rng,
+    count: usize,
+    max_height: usize,
+    value: Option<f32>,
+) {
+    let value = value.unwrap_or_else(|| mean(spectrogram));
+    let (width, height) = spectrogram.shape();
+    for _ in 0..count {
+        let len = rng.usize(0..=max_height.min(height));
+        let start = rng.usize(0..=height - len);
+        spectrogram.data_mut()[start * width..(start + len) * width].fill(value);
+    }
+}
+
+/// Moves the content `columns` to the right, negative to the left. Columns
+/// shifted in are filled with the minimum.
+pub fn time_shift(
+    spectrogram: &ZaoSpectrogram,
+    label: &ZaoaiLabel,
+    columns: isize,
+) -> Result<(ZaoSpectrogram, ZaoaiLabel)> {
+    let seconds_per_column = spectrogram
+        .seconds_per_column()
+        .context("spectrogram has no sample rate or hop")?;
+    let (width, height) = spectrogram.shape();
+    let fill = spectrogram
+        .data()
+        .iter()
+        .copied()
+        .fold(f32::INFINITY, f32::min);
+
+    let mut shifted = spectrogram.clone();
+    for row in 0..height {
+        let source = spectrogram.row(row);
+        let out = &mut shifted.data_mut()[row * width..(row + 1) * width];
+        for (column, value) in out.iter_mut().enumerate() {
+            let from = column as isize - columns;
+            *value = if (0..width as isize).contains(&from) {
+                source[from as usize]
+            } else {
+                fill
+            };
+        }
+    }
+
+    let shift = columns as f64 * seconds_per_column;
+    let duration = width as f64 * seconds_per_column;
+    let label = relabel(label, Duration::from_secs_f64(duration), |t| t + shift);
+    Ok((shifted, label))
+}
+
+/// Keeps `columns`, the label moves with the first kept column.
+pub fn crop(
+    spectrogram: &ZaoSpectrogram,
+    label: &ZaoaiLabel,
+    columns: Range<usize>,
+) -> Result<(ZaoSpectrogram, ZaoaiLabel)> {
+    let seconds_per_column = spectrogram
+        .seconds_per_column()
+        .context("spectrogram has no sample rate or hop")?;
+    let start = columns.start as f64 * seconds_per_column;
+    let cropped = spectrogram.slice_columns(columns);
+    let duration = cropped.width() as f64 * seconds_per_column;
+    let label = relabel(label, Duration::from_secs_f64(duration), |t| t - start);
+    Ok((cropped, label))
+}
+
+/// Resamples the columns as if played `rate` times as fast, the label is scaled to match.
+pub fn time_stretch(
+    spectrogram: &ZaoSpectrogram,
+    label: &ZaoaiLabel,
+    rate: f64,
+) -> Result<(ZaoSpectrogram, ZaoaiLabel)> {
+    anyhow::ensure!(rate > 0.0, "stretch rate must be positive");
+    let seconds_per_column = spectrogram
+        .seconds_per_column()
+        .context("spectrogram has no sample rate or hop")?;
+    let (width, height) = spectrogram.shape();
+    let new_width = ((width as f64 / rate).round() as usize).max(1);
+    let data = resample_grid(spectrogram.data(), width, height, new_width, height);
+
+    // The analysis config no longer matches the columns and is left unset.
+    let mut stretched = ZaoSpectrogram::new(
+        data,
+        new_width,
+        height,
+        spectrogram.frequency_scale,
+        spectrogram.sample_rate,
+        spectrogram.hop,
+    )?;
+    stretched.values = spectrogram.values;
+    stretched.n_fft = spectrogram.n_fft;
+
+    let duration = new_width as f64 * seconds_per_column;
+    let label = relabel(label, Duration::from_secs_f64(duration), |t| t / rate);
+    Ok((stretched, label))
+}
+
+/// Scales the values by `db`, in their own unit.
+pub fn gain(spectrogram: &mut ZaoSpectrogram, db: f32) {
+    let values = spectrogram.values;
+    for v in spectrogram.data_mut() {
+        match values {
+            SpectrogramValues::Decibels => *v += db,
+            SpectrogramValues::Magnitude => *v *= 10f32.powf(db / 20.0),
+            SpectrogramValues::Power => *v *= 10f32.powf(db / 10.0),
+        }
+    }
+}
+
+/// Jitters every cell by Gaussian noise of `std_db` dB.
+pub fn add_noise(spectrogram: &mut ZaoSpectrogram, rng: &mut fastrand::Rng, std_db: f32) {
+    let values = spectrogram.values;
+    for v in spectrogram.data_mut() {
+        let db = gaussian(rng) * std_db;
+        match values {
+            SpectrogramValues::Decibels => *v += db,
+            SpectrogramValues::Magnitude => *v *= 10f32.powf(db / 20.0),
+            SpectrogramValues::Power => *v *= 10f32.powf(db / 10.0),
+        }
+    }
+}
+
+/// Spectrograms of equal shape mixed as `weight * a + (1 - weight) * b`.
+pub fn mix_spectrograms(
+    a: &ZaoSpectrogram,
+    b: &ZaoSpectrogram,
+    weight: f32,
+) -> Result<ZaoSpectrogram> {
+    anyhow::ensure!(
+        a.shape() == b.shape() && a.values == b.values,
+        "mixup needs spectrograms of equal shape and values"
+    );
+    let mut mixed = a.clone();
+    for (m, &v) in mixed.data_mut().iter_mut().zip(b.data()) {
+        *m = weight * *m + (1.0 - weight) * v;
+    }
+    Ok(mixed)
+}
+
+/// Mixup of two windows, their targets mixed with the same weight.
+pub fn mixup(a: &TrainingWindow, b: &TrainingWindow, weight: f32) -> Result<TrainingWindow> {
+    let mix = |x: &[f32], y: &[f32]| -> Vec<f32> {
+        x.iter()
+            .zip(y)
+            .map(|(x, y)| weight * x + (1.0 - weight) * y)
+            .collect()
+    };
+    Ok(TrainingWindow {
+        start_column: a.start_column,
+        offset: a.offset,
+        spectrogram: mix_spectrograms(&a.spectrogram, &b.spectrogram, weight)?,
+        targets: FrameTargets {
+            opening: mix(&a.targets.opening, &b.targets.opening),
+            ending: mix(&a.targets.ending, &b.targets.ending),
+            boundary_distance: mix(&a.targets.boundary_distance, &b.targets.boundary_distance),
+            valid: a
+                .targets
+                .valid
+                .iter()
+                .zip(&b.targets.valid)
+                .map(|(x, y)| *x && *y)
+                .collect(),
+        },
+    })
+}
+
+pub fn audio_gain(samples: &mut [f32], db: f32) {
+    let factor = 10f32.powf(db / 20.0);
+    samples.iter_mut().for_each(|s| *s *= factor);
+}
+
+/// Adds white noise at `snr_db` below the signal's RMS.
+pub fn audio_noise(samples: &mut [f32], rng: &mut fastrand::Rng, snr_db: f32) {
+    let energy: f64 = samples.iter().map(|&s| s as f64 * s as f64).sum();
+    let rms = (energy / samples.len().max(1) as f64).sqrt() as f32;
+    let std = rms * 10f32.powf(-snr_db / 20.0);
+    samples.iter_mut().for_each(|s| *s += gaussian(rng) * std);
+}
+
+/// Delays the audio by `seconds`, negative advances it. Keeps the length.
+pub fn audio_shift(audio: &mut DecodedAudio, label: &ZaoaiLabel, seconds: f64) -> ZaoaiLabel {
+    let len = audio.samples.len();
+    let shift = (seconds * audio.sample_rate as f64).round() as isize;
+    let source = std::mem::take(&mut audio.samples);
+    audio.samples = (0..len as isize)
+        .map(|i| {
+            let from = i - shift;
+            if (0..len as isize).contains(&from) {
+                source[from as usize]
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    let applied = shift as f64 / audio.sample_rate.max(1) as f64;
+    relabel(label, audio.duration(), |t| t + applied)
+}
+
+/// Plays the audio `rate` times as fast by resampling, pitch moves with it.
+pub fn audio_time_stretch(
+    audio: &DecodedAudio,
+    label: &ZaoaiLabel,
+    rate: f64,
+) -> (DecodedAudio, ZaoaiLabel) {
+    let from = (audio.sample_rate as f64 * rate).round().max(1.0) as u32;
+    let stretched = DecodedAudio {
+        samples: resample(&audio.samples, from, audio.sample_rate),
+        sample_rate: audio.sample_rate,
+        ..Default::default()
+    };
+    let rate = from as f64 / audio.sample_rate as f64;
+    let label = relabel(label, stretched.duration(), |t| t / rate);
+    (stretched, label)
+}
+
+/// `label` with its opening mapped by `map` from seconds since the label
+/// origin, relative to augmented data of `duration`.
+fn relabel(label: &ZaoaiLabel, duration: Duration, map: impl Fn(f64) -> f64) -> ZaoaiLabel {
+    let origin = label.window.map_or(0.0, |w| w.start.as_secs_f64());
+    let total = duration.as_secs_f64();
+    let fps = label.metadata.frame_rate as f64;
+
+    let mut out = label.clone();
+    out.window = None;
+    out.metadata.duration = duration;
+    out.metadata.frame_count = (fps > 0.0).then(|| (total * fps).round() as u32);
+
+    let opening = label
+        .opening_start_time
+        .zip(label.opening_end_time)
+        .map(|(start, end)| {
+            let start = map(start.as_secs_f64() - origin).clamp(0.0, total);
+            let end = map(end.as_secs_f64() - origin).clamp(0.0, total);
+            (start, end)
+        })
+        .filter(|(start, end)| end > start);
+    let time = |t: Option<f64>| t.map(Duration::from_secs_f64);
+    let frame = |t: Option<f64>| t.filter(|_| fps > 0.0).map(|t| (t * fps).round() as u32);
+    let normalized = |t: Option<f64>| t.filter(|_| total > 0.0).map(|t| t / total);
+    let (start, end) = (opening.map(|o| o.0), opening.map(|o| o.1));
+
+    out.opening_start_time = time(start);
+    out.opening_end_time = time(end);
+    out.opening_start_frame = frame(start);
+    out.opening_end_frame = frame(end);
+    out.opening_start_normalized = normalized(start);
+    out.opening_end_normalized = normalized(end);
+    out
+}
+
+fn mean(spectrogram: &ZaoSpectrogram) -> f32 {
+    let data = spectrogram.data();
+    data.iter().sum::<f32>() / data.len().max(1) as f32
+}
+
+/// Standard normal sample by Box-Muller.
+fn gaussian(rng: &mut fastrand::Rng) -> f32 {
+    let u1 = rng.f32().max(f32::MIN_POSITIVE);
+    let u2 = rng.f32();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
@@ -0,0 +1,75 @@
+//! Renders saved spectrograms to PNG images.
+//!
+//! Usage: `render_spectrogram [options] <input> <output.png>`, see [`USAGE`].
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use zaoai_types::spectrogram::SpectrogramScale;
+use zaoai_types::spectrogram::render::{RenderOptions, render_spectrogram_file};
+
+const USAGE: &str = "\
+Usage: render_spectrogram [options] <input> <output.png>
+
+Options:
+  --scale <linear|log>    Frequency axis of the image [default: log]
+  --format <format>       gray8, gray16 or a colormap: black-white, white-black,
+                          audacity, rainbow, light-dark, classic [default: black-white]
+  --db-range <min:max>    dB values mapped to black and white [default: image range]
+  --size <width>x<height> Output size [default: spectrogram shape]";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {e:#}\n\n{USAGE}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let mut options = RenderOptions::default();
+    let mut paths: Vec<PathBuf> = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("missing value for {arg}"))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            "--scale" => {
+                options.frequency_scale = match value()?.to_ascii_lowercase().as_str() {
+                    "linear" => SpectrogramScale::Linear,
+                    "log" => SpectrogramScale::Log,
+                    other => anyhow::bail!("unknown scale '{other}'"),
+                }
+            }
+            "--format" => options.format = value()?.parse()?,
+            "--db-range" => options.db_range = Some(parse_pair(&value()?, ':')?),
+            "--size" => options.size = Some(parse_pair(&value()?, 'x')?),
+            _ if arg.starts_with("--") => anyhow::bail!("unknown option {arg}"),
+            _ => paths.push(arg.into()),
+        }
+    }
+
+    let [input, output] = <[PathBuf; 2]>::try_from(paths)
+        .map_err(|_| anyhow::anyhow!("expected an input and an output path"))?;
+    render_spectrogram_file(&input, &output, &options)?;
+    println!("{}", output.display());
+    Ok(())
+}
+
+/// Parses `a<separator>b`, e.g. `-80:0` or `512x256`.
+fn parse_pair<T>(s: &str, separator: char) -> Result<(T, T)>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let (a, b) = s
+        .split_once(separator)
+        .with_context(|| format!("expected <a>{separator}<b>, got '{s}'"))?;
+    Ok((a.trim().parse()?, b.trim().parse()?))
+}
@@ -0,0 +1,384 @@
+//! Candidate section boundaries from silences, energy jumps and spectral novelty.
+//!
+//! Openings usually start or end right next to a short silence or an abrupt
+//! change in loudness or timbre. The candidates found here are used to snap
+//! chapter-derived or predicted opening times onto the actual transition.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ai_labels::ZaoaiLabel;
+use crate::features::{FeatureConfig, Features, compute_features, mean, rms_to_db};
+use crate::sound::{DecodeOptions, decode_audio};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BoundaryKind {
+    /// Audio goes quiet here.
+    SilenceStart,
+    /// Audio resumes after a silence.
+    SilenceEnd,
+    EnergyJump,
+    SpectralNovelty,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BoundaryCandidate {
+    pub time: Duration,
+    /// Combined evidence in `[0, 1]`.
+    pub strength: f32,
+    /// Every detector that fired here.
+    pub kinds: Vec<BoundaryKind>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BoundaryConfig {
+    pub features: FeatureConfig,
+    /// Frames below this RMS level (dBFS) count as silent.
+    pub silence_db: f32,
+    /// Shortest silent run reported.
+    pub min_silence: Duration,
+    /// Silent runs at least this long get full strength.
+    pub full_silence: Duration,
+    /// Mean level before and after a frame is compared over this span.
+    pub energy_window: Duration,
+    /// Smallest level change in dB reported as an energy jump.
+    pub energy_jump_db: f32,
+    /// Level change in dB that gets full strength.
+    pub full_energy_jump_db: f32,
+    /// Mean MFCCs before and after a frame are compared over this span.
+    pub novelty_window: Duration,
+    /// Smallest novelty reported, in standard deviations per coefficient.
+    pub novelty_threshold: f32,
+    /// Novelty that gets full strength.
+    pub full_novelty: f32,
+    /// Energy and novelty peaks must be the maximum within this distance.
+    pub peak_distance: Duration,
+    /// Candidates closer than this are merged into one.
+    pub merge_distance: Duration,
+}
+
+impl Default for BoundaryConfig {
+    fn default() -> Self {
+        Self {
+            features: FeatureConfig {
+                frame_len: 1024,
+                hop: 512,
+                ..FeatureConfig::default()
+            },
+            silence_db: -45.0,
+            min_silence: Duration::from_millis(150),
+            full_silence: Duration::from_secs(1),
+            energy_window: Duration::from_millis(500),
+            energy_jump_db: 6.0,
+            full_energy_jump_db: 20.0,
+            novelty_window: Duration::from_secs(2),
+            novelty_threshold: 1.0,
+            full_novelty: 2.0,
+            peak_distance: Duration::from_secs(1),
+            merge_distance: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Boundary candidates of already computed features, sorted by time.
+pub fn detect_boundaries(features: &Features, config: &BoundaryConfig) -> Vec<BoundaryCandidate> {
+    let mut candidates = silence_boundaries(features, config);
+    candidates.extend(energy_boundaries(features, config));
+    candidates.extend(novelty_boundaries(features, config));
+    merge_candidates(candidates, config.merge_distance)
+}
+
+/// Boundary candidates of in-memory mono `samples`, `offset` being the track time of `samples[0]`.
+pub fn detect_boundaries_in_samples(
+    samples: &[f32],
+    sample_rate: u32,
+    offset: Duration,
+    config: &BoundaryConfig,
+) -> Result<Vec<BoundaryCandidate>> {
+    let features = compute_features(samples, sample_rate, offset, &config.features)?;
+    Ok(detect_boundaries(&features, config))
+}
+
+/// Decodes `path` (through the audio cache, if installed) and detects its boundary candidates.
+pub fn detect_boundaries_in_audio(
+    path: impl AsRef<Path>,
+    decode_options: &DecodeOptions,
+    config: &BoundaryConfig,
+) -> Result<Vec<BoundaryCandidate>> {
+    let path = path.as_ref();
+    let audio = decode_audio(path, decode_options)
+        .with_context(|| format!("Failed to decode: {}", path.display()))?;
+    detect_boundaries_in_samples(&audio.samples, audio.sample_rate, audio.offset, config)
+}
+
+/// Track time of frame `index`, taken at its start so that transitions line up with the audio.
+fn frame_start(features: &Features, index: usize) -> Duration {
+    features.offset + features.hop_duration() * index as u32
+}
+
+fn frames_in(features: &Features, span: Duration) -> usize {
+    let hop = features.hop_duration().as_secs_f64();
+    if hop > 0.0 {
+        ((span.as_secs_f64() / hop).round() as usize).max(1)
+    } else {
+        1
+    }
+}
+
+/// Lowest level used for energy jumps, in dBFS.
+const ENERGY_FLOOR_DB: f32 = -80.0;
+
+fn silence_boundaries(features: &Features, config: &BoundaryConfig) -> Vec<BoundaryCandidate> {
+    let n = features.n_frames();
+    let min_frames = frames_in(features, config.min_silence);
+    let mut candidates = Vec::new();
+
+    let mut frame = 0;
+    while frame < n {
+        if rms_to_db(features.rms[frame]) >= config.silence_db {
+            frame += 1;
+            continue;
+        }
+        let start = frame;
+        while frame < n && rms_to_db(features.rms[frame]) < config.silence_db {
+            frame += 1;
+        }
+        if frame - start < min_frames {
+            continue;
+        }
+
+        let length = features.hop_duration() * (frame - start) as u32;
+        let strength = (length.as_secs_f64() / config.full_silence.as_secs_f64()).min(1.0) as f32;
+        // Silences at the very start or end of the track do not separate anything.
+        if start > 0 {
+            candidates.push(BoundaryCandidate {
+                time: frame_start(features, start),
+                strength,
+                kinds: vec![BoundaryKind::SilenceStart],
+            });
+        }
+        if frame < n {
+            candidates.push(BoundaryCandidate {
+                time: frame_start(features, frame),
+                strength,
+                kinds: vec![BoundaryKind::SilenceEnd],
+            });
+        }
+    }
+    candidates
+}
+
+fn energy_boundaries(features: &Features, config: &BoundaryConfig) -> Vec<BoundaryCandidate> {
+    // Digital silence would otherwise turn every fade into a jump of a hundred dB.
+    let db: Vec<f32> = features
+        .rms
+        .iter()
+        .map(|&r| rms_to_db(r).max(ENERGY_FLOOR_DB))
+        .collect();
+    let width = frames_in(features, config.energy_window);
+    let jumps: Vec<f32> = mean_difference(&db, width)
+        .into_iter()
+        .map(f32::abs)
+        .collect();
+
+    pick_peaks(&jumps, frames_in(features, config.peak_distance))
+        .into_iter()
+        .filter(|&i| jumps[i] >= config.energy_jump_db)
+        .map(|i| BoundaryCandidate {
+            time: frame_start(features, i),
+            strength: (jumps[i] / config.full_energy_jump_db).min(1.0),
+            kinds: vec![BoundaryKind::EnergyJump],
+        })
+        .collect()
+}
+
+fn novelty_boundaries(features: &Features, config: &BoundaryConfig) -> Vec<BoundaryCandidate> {
+    let n = features.n_frames();
+    let dim = features.mfcc.dim;
+    // The 0th coefficient is overall level, which the energy detector already covers.
+    if n == 0 || dim < 2 {
+        return Vec::new();
+    }
+
+    let width = frames_in(features, config.novelty_window);
+    let mut novelty = vec![0f32; n];
+    for c in 1..dim {
+        // Standardized over the track so that every coefficient weighs the same.
+        let values: Vec<f32> = features.mfcc.frames().map(|f| f[c]).collect();
+        let mean = mean(&values);
+        let std = (values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n as f32).sqrt();
+        if std <= 0.0 {
+            continue;
+        }
+        for (total, diff) in novelty.iter_mut().zip(mean_difference(&values, width)) {
+            *total += (diff / std).powi(2);
+        }
+    }
+    for value in &mut novelty {
+        *value = (*value / (dim - 1) as f32).sqrt();
+    }
+
+    pick_peaks(&novelty, frames_in(features, config.peak_distance))
+        .into_iter()
+        .filter(|&i| novelty[i] >= config.novelty_threshold)
+        .map(|i| BoundaryCandidate {
+            time: frame_start(features, i),
+            strength: (novelty[i] / config.full_novelty).min(1.0),
+            kinds: vec![BoundaryKind::SpectralNovelty],
+        })
+        .collect()
+}
+
+/// Mean of the `width` values from each index on minus the mean of the
+/// `width` values before it, 0 where either side is incomplete.
+fn mean_difference(values: &[f32], width: usize) -> Vec<f32> {
+    let mut prefix = Vec::with_capacity(values.len() + 1);
+    prefix.push(0f64);
+    for &v in values {
+        prefix.push(prefix.last().unwrap() + v as f64);
+    }
+
+    (0..values.len())
+        .map(|i| {
+            if i < width || i + width > values.len() {
+                0.0
+            } else {
+                let before = prefix[i] - prefix[i - width];
+                let after = prefix[i + width] - prefix[i];
+                ((after - before) / width as f64) as f32
+            }
+        })
+        .collect()
+}
+
+/// Indices that hold the maximum of `values` within `distance` on both sides, first one on plateaus.
+fn pick_peaks(values: &[f32], distance: usize) -> Vec<usize> {
+    (0..values.len())
+        .filter(|&i| {
+            let lo = i.saturating_sub(distance);
+            let hi = (i + distance + 1).min(values.len());
+            values[i] > 0.0
+                && values[lo..i].iter().all(|&v| v < values[i])
+                && values[i + 1..hi].iter().all(|&v| v <= values[i])
+        })
+        .collect()
+}
+
+/// Sorts candidates and merges those within `distance` of the previous one.
+///
+/// The merged candidate keeps the time of its strongest member, strengths
+/// combine as independent evidence.
+fn merge_candidates(
+    mut candidates: Vec<BoundaryCandidate>,
+    distance: Duration,
+) -> Vec<BoundaryCandidate> {
+    candidates.sort_by_key(|c| c.time);
+
+    let mut merged: Vec<(BoundaryCandidate, f32, Duration)> = Vec::new();
+    for candidate in candidates {
+        match merged.last_mut() {
+            Some((group, best, last)) if candidate.time.saturating_sub(*last) <= distance => {
+                if candidate.strength > *best {
+                    *best = candidate.strength;
+                    group.time = candidate.time;
+                }
+                *last = candidate.time;
+                group.strength = 1.0 - (1.0 - group.strength) * (1.0 - candidate.strength);
+                for kind in candidate.kinds {
+                    if !group.kinds.contains(&kind) {
+                        group.kinds.push(kind);
+                    }
+                }
+            }
+            _ => {
+                let (strength, time) = (candidate.strength, candidate.time);
+                merged.push((candidate, strength, time));
+            }
+        }
+    }
+    merged
+        .into_iter()
+        .map(|(candidate, _, _)| candidate)
+        .collect()
+}
+
+/// The candidate closest to `time` within `tolerance` with at least
+/// `min_strength`, the stronger one on ties.
+pub fn nearest_boundary(
+    candidates: &[BoundaryCandidate],
+    time: Duration,
+    tolerance: Duration,
+    min_strength: f32,
+) -> Option<&BoundaryCandidate> {
+    candidates
+        .iter()
+        .filter(|c| c.strength >= min_strength && c.time.abs_diff(time) <= tolerance)
+        .min_by(|a, b| {
+            a.time
+                .abs_diff(time)
+                .cmp(&b.time.abs_diff(time))
+                .then(b.strength.total_cmp(&a.strength))
+        })
+}
+
+/// `time` moved onto the nearest candidate, or unchanged if there is none within `tolerance`.
+pub fn snap_time(
+    candidates: &[BoundaryCandidate],
+    time: Duration,
+    tolerance: Duration,
+    min_strength: f32,
+) -> Duration {
+    nearest_boundary(candidates, time, tolerance, min_strength).map_or(time, |c| c.time)
+}
+
+/// Snaps an opening's start and end, keeping the end after the start.
+pub fn snap_opening(
+    candidates: &[BoundaryCandidate],
+    start: Duration,
+    end: Duration,
+    tolerance: Duration,
+    min_strength: f32,
+) -> (Duration, Duration) {
+    let snapped_start = snap_time(candidates, start, tolerance, min_strength);
+    let snapped_end = snap_time(candidates, end, tolerance, min_strength);
+    if snapped_end > snapped_start {
+        (snapped_start, snapped_end)
+    } else {
+        (start, end)
+    }
+}
+
+/// Returns a copy of `label` with its opening snapped to `candidates` and the
+/// normalized times updated to match.
+pub fn snap_label(
+    label: &ZaoaiLabel,
+    candidates: &[BoundaryCandidate],
+    tolerance: Duration,
+    min_strength: f32,
+) -> ZaoaiLabel {
+    let (Some(start), Some(end)) = (label.opening_start_time, label.opening_end_time) else {
+        return label.clone();
+    };
+    let (start, end) = snap_opening(candidates, start, end, tolerance, min_strength);
+
+    let snapped = ZaoaiLabel {
+        opening_start_time: Some(start),
+        opening_end_time: Some(end),
+        ..label.clone()
+    };
+    match label.window {
+        Some(window) => snapped.windowed(window),
+        None => {
+            let total_secs = label.metadata.duration.as_secs_f64();
+            let normalize = |t: Duration| (total_secs > 0.0).then(|| t.as_secs_f64() / total_secs);
+            ZaoaiLabel {
+                opening_start_normalized: normalize(start),
+                opening_end_normalized: normalize(end),
+                ..snapped
+            }
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! On-disk cache of decoded PCM, so repeated runs over the same episodes only
+//! decode each one once.
+//!
+//! Entries are keyed by the SHA-256 of the source file plus the decoder
+//! settings, and the least recently used ones are evicted once the cache
+//! grows over its size limit.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::hash::ContentHash;
+use crate::sound::{DecodeReport, DecodedAudio};
+
+/// Bumped whenever the entry layout or the decoder output changes, old entries are then ignored.
+pub const CACHE_FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"ZPCM";
+const ENTRY_EXTENSION: &str = "pcm";
+
+static GLOBAL_CACHE: RwLock<Option<Arc<AudioCache>>> = RwLock::new(None);
+
+/// Installs the cache used by [`crate::sound::decode_audio`] and
+/// [`crate::spectrogram::generate_spectrogram`], `None` disables caching.
+pub fn set_global_cache(cache: Option<AudioCache>) {
+    *GLOBAL_CACHE.write().unwrap() = cache.map(Arc::new);
+}
+
+pub fn global_cache() -> Option<Arc<AudioCache>> {
+    GLOBAL_CACHE.read().unwrap().clone()
+}
+
+pub struct AudioCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Content hashes by path, reused while the file size and mtime are unchanged.
+    hashes: Mutex<HashMap<PathBuf, (u64, SystemTime, ContentHash)>>,
+    evict_lock: Mutex<()>,
+}
+
+impl AudioCache {
+    pub fn new(dir: impl AsRef<Path>, max_bytes: u64) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create cache dir {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            max_bytes,
+            hashes: Mutex::new(HashMap::new()),
+            evict_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Content hash of `path`, only re-read when the file changed since the last call.
+    pub fn content_hash(&self, path: impl AsRef<Path>) -> Result<ContentHash> {
+        let path = path.as_ref();
+        let meta = fs::metadata(path)?;
+        let stamp = (meta.len(), meta.modified()?);
+
+        if let Some(&(len, modified, hash)) = self.hashes.lock().unwrap().get(path)
+            && (len, modified) == stamp
+        {
+            return Ok(hash);
+        }
+
+        let hash = ContentHash::of_file(path)?;
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (stamp.0, stamp.1, hash));
+        Ok(hash)
+    }
+
+    /// Entry key for the decoded audio of `path` under `settings`.
+    ///
+    /// `settings` must include everything that changes the decoded samples,
+    /// it is serialized to JSON and hashed together with the file content.
+    pub fn key(&self, path: impl AsRef<Path>, settings: &impl Serialize) -> Result<String> {
+        let content = self.content_hash(path)?;
+        let settings = serde_json::to_string(settings)?;
+        let key = format!("{CACHE_FORMAT_VERSION}:{content}:{settings}");
+        Ok(ContentHash::of_bytes(key.as_bytes()).to_hex())
+    }
+
+    /// Cached audio for `key`, marking the entry as recently used.
+    pub fn get(&self, key: &str) -> Option<DecodedAudio> {
+        let path = self.entry_path(key);
+        if !path.exists() {
+            return None;
+        }
+
+        match read_entry(&path) {
+            Ok(audio) => {
+                // Eviction goes by mtime, so a hit refreshes it.
+                if let Err(e) = File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_modified(SystemTime::now()))
+                {
+                    log::warn!("Failed to touch cache entry {}: {e}", path.display());
+                }
+                Some(audio)
+            }
+            Err(e) => {
+                log::warn!("Dropping unreadable cache entry {}: {e:?}", path.display());
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    /// Stores `audio` under `key` and evicts old entries if over the limit.
+    pub fn put(&self, key: &str, audio: &DecodedAudio) -> Result<()> {
+        if entry_size(audio) > self.max_bytes {
+            return Ok(());
+        }
+
+        // Write to a unique temporary file first so readers never see half an
+        // entry, it is removed again if anything fails.
+        let path = self.entry_path(key);
+        let tmp = tempfile::NamedTempFile::new_in(&self.dir)?;
+        write_entry(tmp.path(), audio)
+            .and_then(|_| tmp.persist(&path).map(drop).map_err(Into::into))
+            .with_context(|| format!("Failed to write cache entry {}", path.display()))?;
+
+        self.evict()?;
+        Ok(())
+    }
+
+    /// Returns the cached audio of `path` or runs `decode` and caches its result.
+    ///
+    /// Cache failures are logged and fall back to decoding, only errors of
+    /// `decode` itself are returned.
+    pub fn get_or_insert_with<E>(
+        &self,
+        path: impl AsRef<Path>,
+        settings: &impl Serialize,
+        decode: impl FnOnce() -> Result<DecodedAudio, E>,
+    ) -> Result<DecodedAudio, E> {
+        let path = path.as_ref();
+        let key = match self.key(path, settings) {
+            Ok(key) => key,
+            // Most likely the file is unreadable, let the decoder report it.
+            Err(_) => return decode(),
+        };
+
+        if let Some(mut audio) = self.get(&key) {
+            log::debug!("Audio cache hit for {}", path.display());
+            // Identical content may have been cached under another path.
+            audio.report.path = path.to_path_buf();
+            return Ok(audio);
+        }
+
+        let audio = decode()?;
+        if let Err(e) = self.put(&key, &audio) {
+            log::warn!("Failed to cache audio of {}: {e:?}", path.display());
+        }
+        Ok(audio)
+    }
+
+    /// Total size of all entries.
+    pub fn size_bytes(&self) -> Result<u64> {
+        Ok(self.entries()?.iter().map(|e| e.1).sum())
+    }
+
+    /// Removes least recently used entries until the cache fits its limit.
+    /// Returns the number of bytes freed.
+    pub fn evict(&self) -> Result<u64> {
+        let _guard = self.evict_lock.lock().unwrap();
+
+        let mut entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|e| e.1).sum();
+        if total <= self.max_bytes {
+            return Ok(0);
+        }
+
+        entries.sort_by_key(|e| e.2);
+        let mut freed = 0;
+        for (path, len, _) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    total -= len;
+                    freed += len;
+                }
+                // Another process got there first.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => total -= len,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(freed)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        for (path, _, _) in self.entries()? {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(key).with_extension(ENTRY_EXTENSION)
+    }
+
+    /// Every entry with its size and last use.
+    fn entries(&self) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|e| e != ENTRY_EXTENSION) {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            entries.push((path, meta.len(), meta.modified()?));
+        }
+        Ok(entries)
+    }
+}
+
+fn entry_size(audio: &DecodedAudio) -> u64 {
+    audio.samples.len() as u64 * 4
+}
+
+// Entry layout, little endian:
+//   magic "ZPCM", format version u32, sample rate u32, offset in ns u64,
+//   report JSON length u32, report JSON, sample count u64, f32 samples.
+fn write_entry(path: &Path, audio: &DecodedAudio) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let report = serde_json::to_vec(&audio.report)?;
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&CACHE_FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&audio.sample_rate.to_le_bytes())?;
+    writer.write_all(&(audio.offset.as_nanos() as u64).to_le_bytes())?;
+    writer.write_all(&(report.len() as u32).to_le_bytes())?;
+    writer.write_all(&report)?;
+    writer.write_all(&(audio.samples.len() as u64).to_le_bytes())?;
+    for sample in &audio.samples {
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_entry(path: &Path) -> Result<DecodedAudio> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(&magic == MAGIC, "not a cache entry");
+    let version = read_u32(&mut reader)?;
+    anyhow::ensure!(
+        version == CACHE_FORMAT_VERSION,
+        "cache entry version {version}, expected {CACHE_FORMAT_VERSION}"
+    );
+
+    let sample_rate = read_u32(&mut reader)?;
+    let offset = Duration::from_nanos(read_u64(&mut reader)?);
+
+    let report_len = read_u32(&mut reader)? as u64;
+    anyhow::ensure!(report_len < file_len, "corrupt report length");
+    let mut report = vec![0u8; report_len as usize];
+    reader.read_exact(&mut report)?;
+    let report: DecodeReport = serde_json::from_slice(&report)?;
+
+    let n_samples = read_u64(&mut reader)?;
+    let header_len = 4 + 4 + 4 + 8 + 4 + report_len + 8;
+    anyhow::ensure!(
+        n_samples
+            .checked_mul(4)
+            .and_then(|n| n.checked_add(header_len))
+            == Some(file_len),
+        "cache entry is truncated"
+    );
+
+    let mut bytes = vec![0u8; n_samples as usize * 4];
+    reader.read_exact(&mut bytes)?;
+    let samples = bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+
+    Ok(DecodedAudio {
+        samples,
+        sample_rate,
+        offset,
+        report,
+    })
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::loudness::LoudnessMeter;
+
+    #[test]
+    fn entry_with_measured_loudness_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, samples) in [
+            ("silent", vec![0.0; 48_000 * 2]),
+            (
+                "tone",
+                (0..48_000 * 2)
+                    .map(|i| (i as f32 * 0.05).sin() * 0.5)
+                    .collect(),
+            ),
+        ] {
+            let mut meter = LoudnessMeter::new(48_000);
+            meter.push(&samples);
+            let audio = DecodedAudio {
+                samples,
+                sample_rate: 48_000,
+                offset: Duration::from_millis(250),
+                report: DecodeReport {
+                    loudness: Some(meter.finish()),
+                    ..Default::default()
+                },
+            };
+
+            let path = dir.path().join(name).with_extension(ENTRY_EXTENSION);
+            write_entry(&path, &audio).unwrap();
+            let loaded = read_entry(&path).unwrap();
+            assert_eq!(loaded.samples, audio.samples);
+            assert_eq!(loaded.offset, audio.offset);
+            assert_eq!(loaded.report.loudness, audio.report.loudness);
+        }
+    }
+}
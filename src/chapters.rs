@@ -5,10 +5,12 @@ use serde_xml_rs::de::from_str;
 use std::ffi::OsString;
 use std::fmt::Write;
 use std::fs::File;
+use std::io::{BufReader, Read, Seek};
 use std::path::Path;
 use std::time::Duration;
 use std::{fs, process::Command};
 
+use crate::ebml::{self, ControlFlow, ElementHeader};
 use crate::temp::create_temp_file;
 use crate::utils::get_third_party_binary;
 pub fn extract_chapters(mkv_file_path: impl AsRef<Path>) -> anyhow::Result<Option<Chapters>> {
@@ -84,7 +86,7 @@ pub struct VideoMetadata {
 
 impl VideoMetadata {
     pub fn has_chapters(&self) -> bool {
-        self.chapters.len() > 0
+        !self.chapters.is_empty()
     }
 }
 
@@ -94,10 +96,9 @@ pub struct Chapters {
     edition_entry: EditionEntry,
 }
 
-impl Into<Vec<ChapterAtom>> for Chapters {
-    fn into(self) -> Vec<ChapterAtom> {
-        let vec: Vec<ChapterAtom> = self.edition_entry.chapters.to_owned();
-        vec
+impl From<Chapters> for Vec<ChapterAtom> {
+    fn from(chapters: Chapters) -> Self {
+        chapters.edition_entry.chapters
     }
 }
 
@@ -178,6 +179,111 @@ pub fn parse_chapter_xml(xml: &str) -> anyhow::Result<Chapters> {
     Ok(chapters)
 }
 
+const ID_CHAPTERS: u32 = 0x1043_A770;
+const ID_EDITION_ENTRY: u32 = 0x45B9;
+const ID_CHAPTER_ATOM: u32 = 0xB6;
+const ID_CHAPTER_TIME_START: u32 = 0x91;
+const ID_CHAPTER_TIME_END: u32 = 0x92;
+const ID_CHAPTER_DISPLAY: u32 = 0x80;
+const ID_CHAP_STRING: u32 = 0x85;
+
+/// Reads the chapters of a Matroska file without mkvextract.
+///
+/// Only the first edition and its top level atoms are read, the same shape
+/// [`extract_chapters`] produces. Times are formatted like mkvextract does.
+pub fn read_chapters<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<Chapters>> {
+    let mut reader = BufReader::new(reader);
+    let segment = ebml::seek_to_segment(&mut reader)?;
+
+    let mut chapters = None;
+    ebml::for_each_child(&mut reader, segment.data_end(), |reader, header| {
+        if header.id != ID_CHAPTERS {
+            // Chapters may follow the clusters, so keep walking past them.
+            return Ok(ControlFlow::Continue);
+        }
+        ebml::for_each_child(reader, header.data_end(), |reader, edition| {
+            if edition.id != ID_EDITION_ENTRY {
+                return Ok(ControlFlow::Continue);
+            }
+            chapters = Some(read_edition(reader, edition)?);
+            Ok(ControlFlow::Break)
+        })?;
+        Ok(ControlFlow::Break)
+    })?;
+
+    Ok(chapters.filter(|c| c.num_chapters() > 0))
+}
+
+/// [`read_chapters`] on a file path.
+pub fn read_chapters_from_file(path: impl AsRef<Path>) -> anyhow::Result<Option<Chapters>> {
+    read_chapters(&mut File::open(path)?)
+}
+
+fn read_edition<R: Read + Seek>(
+    reader: &mut R,
+    edition: &ElementHeader,
+) -> std::io::Result<Chapters> {
+    let mut chapters = Vec::new();
+    ebml::for_each_child(reader, edition.data_end(), |reader, atom| {
+        if atom.id == ID_CHAPTER_ATOM {
+            chapters.push(read_chapter_atom(reader, atom)?);
+        }
+        Ok(ControlFlow::Continue)
+    })?;
+
+    Ok(Chapters {
+        edition_entry: EditionEntry { chapters },
+    })
+}
+
+fn read_chapter_atom<R: Read + Seek>(
+    reader: &mut R,
+    atom: &ElementHeader,
+) -> std::io::Result<ChapterAtom> {
+    let mut start = 0;
+    let mut end = None;
+    let mut title = None;
+    ebml::for_each_child(reader, atom.data_end(), |reader, field| {
+        let size = field.size.unwrap_or_default();
+        match field.id {
+            ID_CHAPTER_TIME_START => start = ebml::read_uint(reader, size)?,
+            ID_CHAPTER_TIME_END => end = Some(ebml::read_uint(reader, size)?),
+            // Several displays exist for different languages, keep the first.
+            ID_CHAPTER_DISPLAY if title.is_none() => {
+                ebml::for_each_child(reader, field.data_end(), |reader, display| {
+                    if display.id == ID_CHAP_STRING {
+                        title = Some(ebml::read_string(reader, display.size.unwrap_or_default())?);
+                        return Ok(ControlFlow::Break);
+                    }
+                    Ok(ControlFlow::Continue)
+                })?;
+            }
+            _ => {}
+        }
+        Ok(ControlFlow::Continue)
+    })?;
+
+    Ok(ChapterAtom {
+        start_time: format_chapter_time(start),
+        end_time: end.map(format_chapter_time),
+        display: ChapterDisplay {
+            title: title.unwrap_or_default(),
+        },
+    })
+}
+
+/// `"HH:MM:SS.nnnnnnnnn"`, as written by mkvextract.
+fn format_chapter_time(nanos: u64) -> String {
+    let secs = nanos / 1_000_000_000;
+    format!(
+        "{:02}:{:02}:{:02}.{:09}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        nanos % 1_000_000_000
+    )
+}
+
 fn chapters_to_xml(chapters: &Chapters) -> anyhow::Result<String> {
     let inner = serde_xml_rs::to_string(chapters)?;
     Ok(format!(r#"<?xml version="1.0"?>\n{}"#, inner))
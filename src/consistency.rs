@@ -0,0 +1,306 @@
+//! Series-level sanity checks of opening labels.
+//!
+//! Chapter data is sometimes off by a scene or mislabeled on single
+//! episodes. Labels are grouped by series and every opening is compared
+//! against its siblings by duration and by audio fingerprint; outliers are
+//! collected into a report listing the `.zlbl` files to fix or exclude.
+//! Labels only carry openings so far, endings are not checked.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ai_labels::{ZaoaiLabel, ZaoaiLabelsLoader};
+use crate::fingerprint::{Fingerprint, FingerprintConfig, MatchConfig, match_fingerprints};
+use crate::progress::{Stage, run_parallel};
+use crate::propagate::fingerprint_opening;
+use crate::sound::DecodeOptions;
+use crate::utils::series_name;
+
+#[derive(Debug, Clone)]
+pub struct ConsistencyOptions {
+    /// Openings whose duration is further than this from the series median are outliers.
+    pub max_duration_deviation: Duration,
+    /// Compare opening audio, needs decoding every opening.
+    pub check_audio: bool,
+    pub decode: DecodeOptions,
+    pub fingerprint: FingerprintConfig,
+    pub matching: MatchConfig,
+    /// Fraction of an opening that must be found in a sibling's opening to count as the same song.
+    pub min_audio_coverage: f32,
+    /// Series with fewer labels are reported but not checked, there is no majority to compare to.
+    pub min_series_len: usize,
+}
+
+impl Default for ConsistencyOptions {
+    fn default() -> Self {
+        Self {
+            max_duration_deviation: Duration::from_secs(10),
+            check_audio: true,
+            decode: DecodeOptions::default(),
+            fingerprint: FingerprintConfig::default(),
+            matching: MatchConfig::default(),
+            min_audio_coverage: 0.5,
+            min_series_len: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LabelIssue {
+    MissingOpening,
+    DurationOutlier {
+        #[serde(with = "humantime_serde")]
+        duration: Duration,
+        #[serde(with = "humantime_serde")]
+        median: Duration,
+    },
+    /// The opening audio matches fewer than half of the series' other openings.
+    AudioMismatch {
+        /// Fraction of siblings whose opening matched.
+        matching_siblings: f32,
+    },
+    /// The opening audio could not be decoded.
+    AudioUnavailable {
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlaggedLabel {
+    /// The `.zlbl` file.
+    pub label_file: PathBuf,
+    /// The labeled video file.
+    pub path: PathBuf,
+    pub issues: Vec<LabelIssue>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeriesReport {
+    pub name: String,
+    pub episodes: usize,
+    #[serde(with = "humantime_serde")]
+    pub median_opening: Option<Duration>,
+    /// `false` when the series was too small to check.
+    pub checked: bool,
+    pub flagged: Vec<FlaggedLabel>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConsistencyReport {
+    pub series: Vec<SeriesReport>,
+}
+
+impl ConsistencyReport {
+    /// Every flagged `.zlbl` file, e.g. to exclude from training.
+    pub fn flagged_label_files(&self) -> Vec<&Path> {
+        self.series
+            .iter()
+            .flat_map(|s| &s.flagged)
+            .map(|f| f.label_file.as_path())
+            .collect()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut file = File::create(path)
+            .with_context(|| format!("Failed to create file at {}", path.display()))?;
+        let json = serde_json::to_string_pretty(self)?;
+        writeln!(file, "{}", json)?;
+        Ok(())
+    }
+}
+
+/// Series a label belongs to, the parsed series name or else its directory.
+pub fn series_key(label: &ZaoaiLabel) -> String {
+    series_name(&label.path).unwrap_or_else(|| {
+        label
+            .path
+            .parent()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default()
+    })
+}
+
+/// Checks every label in `label_dir`.
+pub fn check_label_dir(
+    label_dir: impl AsRef<Path>,
+    options: &ConsistencyOptions,
+) -> Result<ConsistencyReport> {
+    let loader = ZaoaiLabelsLoader::new(label_dir)?;
+    let labels = loader.load_zaoai_labels()?;
+    let labels: Vec<(PathBuf, ZaoaiLabel)> = loader
+        .label_file_paths
+        .iter()
+        .cloned()
+        .zip(labels)
+        .collect();
+    check_labels(&labels, options)
+}
+
+/// Checks `(label file, label)` pairs series by series.
+///
+/// Progress and cancellation of the audio check come from `options.decode.control`.
+pub fn check_labels(
+    labels: &[(PathBuf, ZaoaiLabel)],
+    options: &ConsistencyOptions,
+) -> Result<ConsistencyReport> {
+    let mut by_series: BTreeMap<String, Vec<&(PathBuf, ZaoaiLabel)>> = BTreeMap::new();
+    for entry in labels {
+        by_series
+            .entry(series_key(&entry.1))
+            .or_default()
+            .push(entry);
+    }
+
+    let checked: Vec<&(PathBuf, ZaoaiLabel)> = by_series
+        .values()
+        .filter(|series| series.len() >= options.min_series_len)
+        .flatten()
+        .copied()
+        .collect();
+    let fingerprints = if options.check_audio {
+        fingerprint_openings(&checked, options)?
+    } else {
+        BTreeMap::new()
+    };
+
+    let series = by_series
+        .into_iter()
+        .map(|(name, series)| check_series(name, &series, &fingerprints, options))
+        .collect();
+    Ok(ConsistencyReport { series })
+}
+
+fn check_series(
+    name: String,
+    series: &[&(PathBuf, ZaoaiLabel)],
+    fingerprints: &BTreeMap<PathBuf, Result<Fingerprint, String>>,
+    options: &ConsistencyOptions,
+) -> SeriesReport {
+    let durations: Vec<Option<Duration>> = series
+        .iter()
+        .map(
+            |(_, label)| match (label.opening_start_time, label.opening_end_time) {
+                (Some(start), Some(end)) => Some(end.saturating_sub(start)),
+                _ => None,
+            },
+        )
+        .collect();
+    let mut sorted: Vec<Duration> = durations.iter().flatten().copied().collect();
+    sorted.sort();
+    let median_opening = sorted.get(sorted.len() / 2).copied();
+
+    let checked = series.len() >= options.min_series_len;
+    let mut flagged = Vec::new();
+    for (i, (label_file, label)) in series.iter().enumerate() {
+        let mut issues = Vec::new();
+        match (durations[i], median_opening) {
+            (None, _) => issues.push(LabelIssue::MissingOpening),
+            (Some(duration), Some(median))
+                if checked && duration.abs_diff(median) > options.max_duration_deviation =>
+            {
+                issues.push(LabelIssue::DurationOutlier { duration, median });
+            }
+            _ => {}
+        }
+
+        if checked && options.check_audio {
+            match fingerprints.get(label_file) {
+                Some(Ok(fingerprint)) => {
+                    if let Some(issue) =
+                        audio_issue(label_file, fingerprint, series, fingerprints, options)
+                    {
+                        issues.push(issue);
+                    }
+                }
+                Some(Err(error)) => issues.push(LabelIssue::AudioUnavailable {
+                    error: error.clone(),
+                }),
+                None => {}
+            }
+        }
+
+        if !issues.is_empty() {
+            flagged.push(FlaggedLabel {
+                label_file: label_file.clone(),
+                path: label.path.clone(),
+                issues,
+            });
+        }
+    }
+
+    SeriesReport {
+        name,
+        episodes: series.len(),
+        median_opening,
+        checked,
+        flagged,
+    }
+}
+
+/// Flags `fingerprint` when it matches fewer than half of its fingerprinted siblings.
+fn audio_issue(
+    label_file: &Path,
+    fingerprint: &Fingerprint,
+    series: &[&(PathBuf, ZaoaiLabel)],
+    fingerprints: &BTreeMap<PathBuf, Result<Fingerprint, String>>,
+    options: &ConsistencyOptions,
+) -> Option<LabelIssue> {
+    let siblings: Vec<&Fingerprint> = series
+        .iter()
+        .filter(|(file, _)| file != label_file)
+        .filter_map(|(file, _)| fingerprints.get(file)?.as_ref().ok())
+        .collect();
+    if siblings.is_empty() {
+        return None;
+    }
+
+    let duration = fingerprint.duration().as_secs_f64().max(f64::MIN_POSITIVE);
+    let matching = siblings
+        .iter()
+        .filter(|sibling| {
+            let covered: f64 = match_fingerprints(fingerprint, sibling, &options.matching)
+                .iter()
+                .map(|r| r.query_end.saturating_sub(r.query_start).as_secs_f64())
+                .sum();
+            covered / duration >= options.min_audio_coverage as f64
+        })
+        .count();
+
+    let matching_siblings = matching as f32 / siblings.len() as f32;
+    (matching_siblings < 0.5).then_some(LabelIssue::AudioMismatch { matching_siblings })
+}
+
+/// Fingerprints the opening of every label with one, in parallel, by label file.
+fn fingerprint_openings(
+    labels: &[&(PathBuf, ZaoaiLabel)],
+    options: &ConsistencyOptions,
+) -> Result<BTreeMap<PathBuf, Result<Fingerprint, String>>> {
+    let labels: Vec<&(PathBuf, ZaoaiLabel)> = labels
+        .iter()
+        .filter(|(_, l)| l.opening_start_time.is_some() && l.opening_end_time.is_some())
+        .copied()
+        .collect();
+    let openings: Vec<&ZaoaiLabel> = labels.iter().map(|(_, label)| label).collect();
+    let results = run_parallel(
+        &openings,
+        &options.decode.control,
+        Stage::Fingerprinting,
+        |label| {
+            fingerprint_opening(label, &options.decode, &options.fingerprint)
+                .map_err(|e| format!("{e:#}"))
+        },
+    )?;
+
+    Ok(labels
+        .iter()
+        .map(|(label_file, _)| label_file.clone())
+        .zip(results)
+        .collect())
+}
@@ -0,0 +1,125 @@
+//! Versioned container for the bincode files of the crate, e.g. features
+//! and pyramids, following the spectrogram format.
+//!
+//! Layout, little endian:
+//!   magic, format version u32, payload length u64, bincode payload,
+//!   SHA-256 of everything before it.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
+const CHECKSUM_LEN: usize = 32;
+const HEADER_LEN: usize = 4 + 4 + 8;
+
+/// Magic and version of one kind of file.
+pub(crate) struct Container {
+    pub magic: &'static [u8; 4],
+    /// Bumped whenever the encoded type changes incompatibly.
+    pub version: u32,
+    /// Used in error messages, e.g. `"features"`.
+    pub name: &'static str,
+}
+
+impl Container {
+    pub fn encode<T: bincode::Encode>(&self, value: &T) -> Result<Vec<u8>> {
+        let payload = bincode::encode_to_vec(value, BINCODE_CONFIG)?;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len() + CHECKSUM_LEN);
+        bytes.extend_from_slice(self.magic);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        let checksum = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&checksum);
+        Ok(bytes)
+    }
+
+    /// Parses and validates a file of this kind in the current version.
+    pub fn decode<T: bincode::Decode<()>>(&self, bytes: &[u8]) -> Result<T> {
+        let name = self.name;
+        anyhow::ensure!(bytes.starts_with(self.magic), "not a {name} file");
+        anyhow::ensure!(
+            bytes.len() >= HEADER_LEN + CHECKSUM_LEN,
+            "{name} file is truncated"
+        );
+
+        let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        anyhow::ensure!(
+            Sha256::digest(body).as_slice() == checksum,
+            "{name} file checksum mismatch"
+        );
+
+        let version = u32::from_le_bytes(body[4..8].try_into()?);
+        anyhow::ensure!(
+            version == self.version,
+            "{name} format version {version}, expected {}",
+            self.version
+        );
+        let payload_len = u64::from_le_bytes(body[8..HEADER_LEN].try_into()?);
+        let payload = &body[HEADER_LEN..];
+        anyhow::ensure!(
+            payload_len == payload.len() as u64,
+            "{name} payload length mismatch"
+        );
+
+        let (value, read) = bincode::decode_from_slice(payload, BINCODE_CONFIG)
+            .with_context(|| format!("Invalid {name} payload"))?;
+        anyhow::ensure!(read == payload.len(), "trailing bytes after {name} payload");
+        Ok(value)
+    }
+
+    /// Writes to a temporary file first, so readers never see half a file.
+    pub fn save<T: bincode::Encode>(&self, value: &T, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let bytes = self.encode(value)?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let write = || -> Result<()> {
+            let tmp = tempfile::NamedTempFile::new_in(dir)?;
+            std::fs::write(tmp.path(), &bytes)?;
+            tmp.persist(path)?;
+            Ok(())
+        };
+        write().with_context(|| format!("Failed to create file at {}", path.display()))
+    }
+
+    pub fn load<T: bincode::Decode<()>>(&self, path: impl AsRef<Path>) -> Result<T> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| format!("{}", path.display()))?;
+        self.decode(&bytes)
+            .with_context(|| format!("Failed to load {} file {}", self.name, path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST: Container = Container {
+        magic: b"ZTST",
+        version: 2,
+        name: "test",
+    };
+
+    #[test]
+    fn rejects_other_versions_and_corrupt_files() {
+        let value = (7u32, vec![1.5f32, -2.0]);
+        let bytes = TEST.encode(&value).unwrap();
+        assert_eq!(TEST.decode::<(u32, Vec<f32>)>(&bytes).unwrap(), value);
+
+        let old = Container { version: 1, ..TEST }.encode(&value).unwrap();
+        assert!(TEST.decode::<(u32, Vec<f32>)>(&old).is_err());
+
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_LEN] ^= 1;
+        assert!(TEST.decode::<(u32, Vec<f32>)>(&corrupt).is_err());
+        assert!(
+            TEST.decode::<(u32, Vec<f32>)>(&bytes[..bytes.len() - 1])
+                .is_err()
+        );
+    }
+}
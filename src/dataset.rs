@@ -0,0 +1,193 @@
+//! Training examples cut from time-calibrated spectrograms.
+//!
+//! An episode spectrogram on a [`TimeGrid`] is split into overlapping
+//! windows of a fixed number of columns. Every column of a window gets its
+//! own targets: how much of it lies in the opening or ending, and how far
+//! it is from the nearest section boundary.
+//!
+//! [`TimeGrid`]: crate::spectrogram::columns::TimeGrid
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::ai_labels::{ZaoaiLabel, ZaoaiLabelsLoader};
+use crate::spectrogram::stats::{BinStats, Normalization};
+use crate::spectrogram::{ZaoSpectrogram, load_spectrogram, load_spectrogram_normalized};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindowConfig {
+    /// Columns per window.
+    pub width: usize,
+    /// Columns between the starts of two windows.
+    pub stride: usize,
+    /// Boundary distances are clamped to this many seconds.
+    pub max_boundary_distance: f64,
+    /// Emit a last window for the columns left after the final full window,
+    /// padded with the spectrogram's minimum.
+    pub pad_last: bool,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            width: 256,
+            stride: 128,
+            max_boundary_distance: 30.0,
+            pad_last: true,
+        }
+    }
+}
+
+/// Per-column targets of one window.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameTargets {
+    /// Fraction of each column inside the opening, in `[0, 1]`.
+    pub opening: Vec<f32>,
+    /// Fraction of each column inside the ending. Labels carry no endings
+    /// yet, so this is all zero for now.
+    pub ending: Vec<f32>,
+    /// Seconds from each column's centre to the nearest opening or ending
+    /// start or end, clamped to [`WindowConfig::max_boundary_distance`].
+    pub boundary_distance: Vec<f32>,
+    /// `false` for padded columns past the end of the audio.
+    pub valid: Vec<bool>,
+}
+
+impl FrameTargets {
+    fn slice(&self, start: usize, end: usize) -> Self {
+        Self {
+            opening: self.opening[start..end].to_vec(),
+            ending: self.ending[start..end].to_vec(),
+            boundary_distance: self.boundary_distance[start..end].to_vec(),
+            valid: self.valid[start..end].to_vec(),
+        }
+    }
+
+    fn pad(&mut self, width: usize, max_boundary_distance: f32) {
+        self.opening.resize(width, 0.0);
+        self.ending.resize(width, 0.0);
+        self.boundary_distance.resize(width, max_boundary_distance);
+        self.valid.resize(width, false);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrainingWindow {
+    /// Column of the episode spectrogram the window starts at.
+    pub start_column: usize,
+    /// Time of the first column from the start of the episode spectrogram.
+    pub offset: Duration,
+    pub spectrogram: ZaoSpectrogram,
+    pub targets: FrameTargets,
+}
+
+/// Targets of every column of `spectrogram` for `label`. Column 0 is the
+/// start of the label window for windowed labels.
+pub fn frame_targets(
+    spectrogram: &ZaoSpectrogram,
+    label: &ZaoaiLabel,
+    max_boundary_distance: f64,
+) -> Result<FrameTargets> {
+    let seconds_per_column = spectrogram
+        .seconds_per_column()
+        .context("spectrogram has no sample rate or hop")?;
+    let opening = spectrogram.label_coverage(label)?;
+    let ending = vec![0.0; spectrogram.width()];
+
+    let origin = label.window.map_or(0.0, |w| w.start.as_secs_f64());
+    let boundaries: Vec<f64> = [label.opening_start_time, label.opening_end_time]
+        .into_iter()
+        .flatten()
+        .map(|t| t.as_secs_f64() - origin)
+        .collect();
+    let boundary_distance = (0..spectrogram.width())
+        .map(|i| {
+            let center = (i as f64 + 0.5) * seconds_per_column;
+            boundaries
+                .iter()
+                .map(|b| (center - b).abs())
+                .fold(max_boundary_distance, f64::min) as f32
+        })
+        .collect();
+
+    Ok(FrameTargets {
+        opening,
+        ending,
+        boundary_distance,
+        valid: vec![true; spectrogram.width()],
+    })
+}
+
+/// Cuts `spectrogram` into overlapping windows with their targets.
+pub fn sliding_windows(
+    spectrogram: &ZaoSpectrogram,
+    label: &ZaoaiLabel,
+    config: &WindowConfig,
+) -> Result<Vec<TrainingWindow>> {
+    anyhow::ensure!(
+        config.width > 0 && config.stride > 0,
+        "window width and stride must be non-zero"
+    );
+    let seconds_per_column = spectrogram
+        .seconds_per_column()
+        .context("spectrogram has no sample rate or hop")?;
+    let targets = frame_targets(spectrogram, label, config.max_boundary_distance)?;
+    let total = spectrogram.width();
+
+    let mut starts: Vec<usize> = (0..=total.saturating_sub(config.width))
+        .step_by(config.stride)
+        .filter(|start| start + config.width <= total)
+        .collect();
+    let next = starts.last().map_or(0, |s| s + config.stride);
+    if config.pad_last && next < total {
+        starts.push(next);
+    }
+    let pad = spectrogram
+        .data()
+        .iter()
+        .copied()
+        .fold(f32::INFINITY, f32::min);
+
+    Ok(starts
+        .into_iter()
+        .map(|start| {
+            let end = (start + config.width).min(total);
+            let mut window_targets = targets.slice(start, end);
+            window_targets.pad(config.width, config.max_boundary_distance as f32);
+            TrainingWindow {
+                start_column: start,
+                offset: Duration::from_secs_f64(start as f64 * seconds_per_column),
+                spectrogram: spectrogram
+                    .slice_columns(start..end)
+                    .fit_width(config.width, Some(pad)),
+                targets: window_targets,
+            }
+        })
+        .collect())
+}
+
+/// Windows of a saved spectrogram and its `.zlbl` label.
+pub fn load_training_windows(
+    spectrogram_path: impl AsRef<Path>,
+    label_path: impl AsRef<Path>,
+    config: &WindowConfig,
+) -> Result<Vec<TrainingWindow>> {
+    let spectrogram = load_spectrogram(spectrogram_path)?;
+    let label = ZaoaiLabelsLoader::load_single(label_path)?;
+    sliding_windows(&spectrogram, &label, config)
+}
+
+/// [`load_training_windows`] with the spectrogram normalized by `stats`.
+pub fn load_training_windows_normalized(
+    spectrogram_path: impl AsRef<Path>,
+    label_path: impl AsRef<Path>,
+    config: &WindowConfig,
+    stats: &BinStats,
+    method: Normalization,
+) -> Result<Vec<TrainingWindow>> {
+    let spectrogram = load_spectrogram_normalized(spectrogram_path, stats, method)?;
+    let label = ZaoaiLabelsLoader::load_single(label_path)?;
+    sliding_windows(&spectrogram, &label, config)
+}
@@ -0,0 +1,184 @@
+//! Minimal EBML reader, just enough to pull the few Matroska elements that
+//! symphonia does not expose. Not a general purpose demuxer.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub const ID_EBML: u32 = 0x1A45_DFA3;
+pub const ID_SEGMENT: u32 = 0x1853_8067;
+pub const ID_CLUSTER: u32 = 0x1F43_B675;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ElementHeader {
+    pub id: u32,
+    /// `None` for elements of unknown size (live streams, some muxers' Segment/Cluster).
+    pub size: Option<u64>,
+    /// Stream position of the first byte of the element data.
+    pub data_start: u64,
+}
+
+impl ElementHeader {
+    pub fn data_end(&self) -> Option<u64> {
+        self.size.map(|size| self.data_start + size)
+    }
+}
+
+/// Reads an element ID, keeping the length marker bits as Matroska IDs are written with them.
+pub fn read_id<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let first = read_u8(reader)?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 4 {
+        return Err(invalid("EBML element ID longer than 4 bytes"));
+    }
+
+    let mut id = first as u32;
+    for _ in 1..len {
+        id = (id << 8) | read_u8(reader)? as u32;
+    }
+    Ok(id)
+}
+
+/// Reads an element data size, `None` meaning "unknown" (all value bits set).
+pub fn read_size<R: Read>(reader: &mut R) -> io::Result<Option<u64>> {
+    let first = read_u8(reader)?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return Err(invalid("EBML size longer than 8 bytes"));
+    }
+
+    let mut value = (first as u64) & (0xFF >> len);
+    for _ in 1..len {
+        value = (value << 8) | read_u8(reader)? as u64;
+    }
+
+    let unknown = (1u64 << (7 * len)) - 1;
+    Ok((value != unknown).then_some(value))
+}
+
+pub fn read_header<R: Read + Seek>(reader: &mut R) -> io::Result<ElementHeader> {
+    let id = read_id(reader)?;
+    let size = read_size(reader)?;
+    let data_start = reader.stream_position()?;
+    Ok(ElementHeader {
+        id,
+        size,
+        data_start,
+    })
+}
+
+pub fn read_uint<R: Read>(reader: &mut R, size: u64) -> io::Result<u64> {
+    if size > 8 {
+        return Err(invalid("EBML unsigned integer longer than 8 bytes"));
+    }
+    let mut value = 0u64;
+    for _ in 0..size {
+        value = (value << 8) | read_u8(reader)? as u64;
+    }
+    Ok(value)
+}
+
+pub fn read_float<R: Read>(reader: &mut R, size: u64) -> io::Result<f64> {
+    match size {
+        0 => Ok(0.0),
+        4 => {
+            let mut buf = [0u8; 4];
+            reader.read_exact(&mut buf)?;
+            Ok(f32::from_be_bytes(buf) as f64)
+        }
+        8 => {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            Ok(f64::from_be_bytes(buf))
+        }
+        _ => Err(invalid("EBML float must be 0, 4 or 8 bytes")),
+    }
+}
+
+/// Reads a string or UTF-8 element, dropping trailing NUL padding.
+pub fn read_string<R: Read>(reader: &mut R, size: u64) -> io::Result<String> {
+    // Strings in the elements we care about are short, anything large is a corrupt size.
+    if size > 1 << 20 {
+        return Err(invalid("EBML string element too large"));
+    }
+    let mut buf = vec![0u8; size as usize];
+    reader.read_exact(&mut buf)?;
+    let text = String::from_utf8_lossy(&buf);
+    Ok(text.trim_end_matches('\0').to_string())
+}
+
+/// Seeks past the data of `header`.
+pub fn skip<R: Seek>(reader: &mut R, header: &ElementHeader) -> io::Result<()> {
+    let end = header
+        .data_end()
+        .ok_or_else(|| invalid("cannot skip an element of unknown size"))?;
+    reader.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Calls `visit` for every child of a master element spanning `[start, end)`.
+///
+/// `visit` must either consume the child data or leave the reader where it
+/// was, the reader is repositioned to the next sibling either way.
+pub fn for_each_child<R, F>(reader: &mut R, end: Option<u64>, mut visit: F) -> io::Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(&mut R, &ElementHeader) -> io::Result<ControlFlow>,
+{
+    loop {
+        let pos = reader.stream_position()?;
+        if end.is_some_and(|end| pos >= end) {
+            return Ok(());
+        }
+
+        let header = match read_header(reader) {
+            Ok(header) => header,
+            // Running out of data while looking for the next sibling just ends the walk.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if visit(reader, &header)? == ControlFlow::Break {
+            return Ok(());
+        }
+
+        match header.data_end() {
+            Some(next) => {
+                reader.seek(SeekFrom::Start(next))?;
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlFlow {
+    Continue,
+    Break,
+}
+
+/// Positions `reader` at the data of the first Segment and returns its header.
+pub fn seek_to_segment<R: Read + Seek>(reader: &mut R) -> io::Result<ElementHeader> {
+    reader.seek(SeekFrom::Start(0))?;
+    let ebml = read_header(reader)?;
+    if ebml.id != ID_EBML {
+        return Err(invalid("not an EBML file"));
+    }
+    skip(reader, &ebml)?;
+
+    loop {
+        let header = read_header(reader)?;
+        if header.id == ID_SEGMENT {
+            return Ok(header);
+        }
+        skip(reader, &header)?;
+    }
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
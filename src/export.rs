@@ -0,0 +1,181 @@
+//! Audio clip export of labeled openings, for listening through labels by ear.
+
+pub mod flac;
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ai_labels::{ZaoaiLabel, ZaoaiLabelsLoader};
+use crate::file::relative_path_from_base;
+use crate::progress::{Stage, run_parallel};
+use crate::sound::{DecodeOptions, TimeRange, decode_audio};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ClipFormat {
+    /// 16-bit PCM WAV.
+    #[default]
+    Wav,
+    /// 16-bit FLAC, see [`flac`].
+    Flac,
+}
+
+impl ClipFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ClipFormat::Wav => "wav",
+            ClipFormat::Flac => "flac",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClipExportOptions {
+    pub format: ClipFormat,
+    /// Audio kept before the labeled opening start.
+    pub pre_roll: Duration,
+    /// Audio kept after the labeled opening end.
+    pub post_roll: Duration,
+    /// Decoder settings, the range is overwritten with the clip range.
+    /// Its `control` also receives per-label progress of batch exports and
+    /// stops them once cancelled.
+    pub decode: DecodeOptions,
+}
+
+/// Outcome of [`export_label_dir_clips`].
+#[derive(Debug, Default)]
+pub struct ClipExportSummary {
+    pub written: Vec<PathBuf>,
+    /// Labels without an opening.
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// Range of the labeled opening widened by the pre- and post-roll, `None` if
+/// the label has no opening times.
+pub fn opening_clip_range(label: &ZaoaiLabel, options: &ClipExportOptions) -> Option<TimeRange> {
+    let start = label.opening_start_time?;
+    let end = label.opening_end_time?;
+    Some(TimeRange::new(
+        start.saturating_sub(options.pre_roll),
+        end + options.post_roll,
+    ))
+}
+
+/// Decodes the opening of `label` and writes it to `out_path`.
+pub fn export_opening_clip(
+    label: &ZaoaiLabel,
+    out_path: impl AsRef<Path>,
+    options: &ClipExportOptions,
+) -> Result<()> {
+    let out_path = out_path.as_ref();
+    let range = opening_clip_range(label, options)
+        .with_context(|| format!("Label has no opening: {}", label.path.display()))?;
+
+    let decode_options = options.decode.clone().with_range(range);
+    let audio = decode_audio(&label.path, &decode_options)
+        .with_context(|| format!("Failed to decode: {}", label.path.display()))?;
+
+    if let Some(parent) = out_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_clip(out_path, &audio.samples, audio.sample_rate, options.format)
+        .with_context(|| format!("Failed to write clip: {}", out_path.display()))
+}
+
+/// Writes mono `samples` to `path` in `format`.
+pub fn write_clip(
+    path: impl AsRef<Path>,
+    samples: &[f32],
+    sample_rate: u32,
+    format: ClipFormat,
+) -> Result<()> {
+    match format {
+        ClipFormat::Wav => write_wav(path, samples, sample_rate),
+        ClipFormat::Flac => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            flac::write_flac(&mut writer, samples, sample_rate)?;
+            writer.flush()?;
+            Ok(())
+        }
+    }
+}
+
+pub fn write_wav(path: impl AsRef<Path>, samples: &[f32], sample_rate: u32) -> Result<()> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for &sample in samples {
+        writer.write_sample(flac::f32_to_i16(sample))?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Exports the opening of every `.zlbl` under `label_dir` into `out_dir`,
+/// mirroring the label directory layout. A label at `a/b/ep01.zlbl` ends up
+/// as `out_dir/a/b/ep01.wav`.
+///
+/// Failing labels are collected in the summary instead of aborting the batch,
+/// a cancelled batch returns [`crate::progress::Cancelled`].
+pub fn export_label_dir_clips(
+    label_dir: impl AsRef<Path>,
+    out_dir: impl AsRef<Path>,
+    options: &ClipExportOptions,
+) -> Result<ClipExportSummary> {
+    let label_dir = label_dir.as_ref();
+    let out_dir = out_dir.as_ref();
+    let loader = ZaoaiLabelsLoader::new(label_dir)?;
+
+    let results = run_parallel(
+        &loader.label_file_paths,
+        &options.decode.control,
+        Stage::Exporting,
+        |label_path| export_label_file(label_path, label_dir, out_dir, options),
+    )?;
+
+    let mut summary = ClipExportSummary::default();
+    for (label_path, result) in loader.label_file_paths.iter().zip(results) {
+        match result {
+            Ok(Some(clip_path)) => {
+                log::info!("Exported clip: {}", clip_path.display());
+                summary.written.push(clip_path);
+            }
+            Ok(None) => summary.skipped.push(label_path.clone()),
+            Err(e) => {
+                log::error!("Clip export failed for {}: {e:?}", label_path.display());
+                summary.failed.push((label_path.clone(), e));
+            }
+        }
+    }
+    summary.written.sort();
+    summary.skipped.sort();
+    summary.failed.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(summary)
+}
+
+fn export_label_file(
+    label_path: &Path,
+    label_dir: &Path,
+    out_dir: &Path,
+    options: &ClipExportOptions,
+) -> Result<Option<PathBuf>> {
+    let label = ZaoaiLabelsLoader::load_single(label_path)?;
+    if label.opening_start_time.is_none() || label.opening_end_time.is_none() {
+        return Ok(None);
+    }
+
+    let clip_path = out_dir
+        .join(relative_path_from_base(label_path, label_dir)?)
+        .with_extension(options.format.extension());
+    export_opening_clip(&label, &clip_path, options)?;
+    Ok(Some(clip_path))
+}
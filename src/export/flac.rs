@@ -0,0 +1,249 @@
+//! Small FLAC encoder for 16-bit mono clips.
+//!
+//! Uses fixed-blocksize frames with the best of the FLAC "fixed" predictors
+//! (order 0 to 4) and a single Rice partition per subframe. That leaves some
+//! compression on the table compared to libFLAC, but is plenty for QA clips
+//! and decodes with every FLAC player.
+
+use std::io::Write;
+
+use anyhow::Result;
+
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_FIXED_ORDER: usize = 4;
+/// 4-bit Rice parameters go up to 14, 15 is the escape code.
+const MAX_RICE_PARAM: u32 = 14;
+
+/// Writes `samples` (mono, `[-1.0, 1.0]`) as a 16-bit FLAC stream.
+pub fn write_flac<W: Write>(writer: &mut W, samples: &[f32], sample_rate: u32) -> Result<()> {
+    anyhow::ensure!(
+        sample_rate > 0 && sample_rate < (1 << 20),
+        "FLAC sample rate out of range: {sample_rate}"
+    );
+
+    let pcm: Vec<i32> = samples.iter().map(|&s| f32_to_i16(s) as i32).collect();
+
+    writer.write_all(b"fLaC")?;
+    writer.write_all(&stream_info(pcm.len() as u64, sample_rate))?;
+
+    for (frame_number, block) in pcm.chunks(BLOCK_SIZE).enumerate() {
+        writer.write_all(&encode_frame(block, frame_number as u64))?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn f32_to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+/// STREAMINFO metadata block, flagged as the last metadata block.
+fn stream_info(total_samples: u64, sample_rate: u32) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    bits.write(1, 1); // last metadata block
+    bits.write(0, 7); // STREAMINFO
+    bits.write(34, 24);
+
+    bits.write(BLOCK_SIZE as u64, 16); // min block size
+    bits.write(BLOCK_SIZE as u64, 16); // max block size
+    bits.write(0, 24); // min frame size, unknown
+    bits.write(0, 24); // max frame size, unknown
+    bits.write(sample_rate as u64, 20);
+    bits.write(0, 3); // channels - 1
+    bits.write((BITS_PER_SAMPLE - 1) as u64, 5);
+    bits.write(total_samples, 36);
+    for _ in 0..16 {
+        bits.write(0, 8); // MD5 of the audio, all zero means not computed
+    }
+
+    bits.into_bytes()
+}
+
+fn encode_frame(block: &[i32], frame_number: u64) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+
+    bits.write(0b11_1111_1111_1110, 14); // sync code
+    bits.write(0, 1); // reserved
+    bits.write(0, 1); // fixed blocksize stream
+    bits.write(0b0111, 4); // block size stored as 16 bits after the header
+    bits.write(0b0000, 4); // sample rate from STREAMINFO
+    bits.write(0b0000, 4); // mono
+    bits.write(0b100, 3); // 16 bits per sample
+    bits.write(0, 1); // reserved
+    write_utf8_number(&mut bits, frame_number);
+    bits.write((block.len() - 1) as u64, 16);
+    let crc8 = crc8(bits.bytes());
+    bits.write(crc8 as u64, 8);
+
+    encode_subframe(&mut bits, block);
+
+    bits.pad_to_byte();
+    let crc16 = crc16(bits.bytes());
+    bits.write(crc16 as u64, 16);
+    bits.into_bytes()
+}
+
+fn encode_subframe(bits: &mut BitWriter, block: &[i32]) {
+    let max_order = MAX_FIXED_ORDER.min(block.len().saturating_sub(1));
+    let (order, residual) = (0..=max_order)
+        .map(|order| (order, fixed_residual(block, order)))
+        .min_by_key(|(_, residual)| {
+            residual
+                .iter()
+                .map(|r| r.unsigned_abs() as u64)
+                .sum::<u64>()
+        })
+        .expect("at least order 0 is always available");
+
+    bits.write(0, 1); // padding
+    bits.write(0b001000 | order as u64, 6); // SUBFRAME_FIXED
+    bits.write(0, 1); // no wasted bits
+
+    for &warm_up in &block[..order] {
+        bits.write_signed(warm_up as i64, BITS_PER_SAMPLE);
+    }
+
+    let param = best_rice_param(&residual);
+    bits.write(0b00, 2); // Rice coding with 4-bit parameters
+    bits.write(0, 4); // partition order 0
+    bits.write(param as u64, 4);
+    for &r in &residual {
+        bits.write_rice(r, param);
+    }
+}
+
+/// Residual of the fixed polynomial predictor of `order`, one value per sample after the warm-up.
+fn fixed_residual(block: &[i32], order: usize) -> Vec<i32> {
+    (order..block.len())
+        .map(|i| {
+            let x = |k: usize| block[i - k] as i64;
+            let r = match order {
+                0 => x(0),
+                1 => x(0) - x(1),
+                2 => x(0) - 2 * x(1) + x(2),
+                3 => x(0) - 3 * x(1) + 3 * x(2) - x(3),
+                _ => x(0) - 4 * x(1) + 6 * x(2) - 4 * x(3) + x(4),
+            };
+            r as i32
+        })
+        .collect()
+}
+
+fn best_rice_param(residual: &[i32]) -> u32 {
+    let cost = |k: u32| -> u64 {
+        residual
+            .iter()
+            .map(|&r| (zigzag(r) >> k) as u64 + 1 + k as u64)
+            .sum()
+    };
+    (0..=MAX_RICE_PARAM)
+        .min_by_key(|&k| cost(k))
+        .unwrap_or_default()
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+/// FLAC's UTF-8 style variable length coding of the frame number.
+fn write_utf8_number(bits: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        bits.write(value, 8);
+        return;
+    }
+
+    let mut continuation = 1;
+    while value >= 1 << (5 * continuation + 6) {
+        continuation += 1;
+    }
+
+    let lead_marker = (0xFF00u64 >> (continuation + 1)) & 0xFF;
+    bits.write(lead_marker | (value >> (6 * continuation)), 8);
+    for i in (0..continuation).rev() {
+        bits.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// MSB-first bit writer.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u8,
+    filled: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            self.push_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64 & ((1u64 << bits) - 1), bits);
+    }
+
+    fn write_rice(&mut self, value: i32, param: u32) {
+        let u = zigzag(value);
+        for _ in 0..(u >> param) {
+            self.push_bit(false);
+        }
+        self.push_bit(true);
+        self.write(u as u64, param);
+    }
+
+    fn push_bit(&mut self, bit: bool) {
+        self.current = (self.current << 1) | bit as u8;
+        self.filled += 1;
+        if self.filled == 8 {
+            self.bytes.push(self.current);
+            self.current = 0;
+            self.filled = 0;
+        }
+    }
+
+    fn pad_to_byte(&mut self) {
+        while self.filled != 0 {
+            self.push_bit(false);
+        }
+    }
+
+    /// Completed bytes so far, the partial byte is not included.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.pad_to_byte();
+        self.bytes
+    }
+}
@@ -0,0 +1,415 @@
+//! Per-frame audio descriptors: MFCC, chroma, spectral centroid, rolloff,
+//! flux, zero-crossing rate and RMS energy, all on one shared frame grid.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+
+use crate::container::Container;
+use crate::filterbank::{MelFilterbank, MelScale};
+use crate::sound::{AudioStream, DecodeOptions, decode_audio, frames_to_duration};
+
+/// Bumped whenever [`Features`] changes incompatibly.
+pub const FEATURES_FORMAT_VERSION: u32 = 1;
+
+const CONTAINER: Container = Container {
+    magic: b"ZFEA",
+    version: FEATURES_FORMAT_VERSION,
+    name: "features",
+};
+
+/// Floor applied before taking logs of mel energies.
+const LOG_FLOOR: f32 = 1e-10;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct FeatureConfig {
+    /// Analysis frame length in samples, also the FFT size.
+    pub frame_len: usize,
+    /// Distance between frame starts in samples, shared by every feature.
+    pub hop: usize,
+    pub n_mels: usize,
+    pub n_mfcc: usize,
+    pub fmin: f32,
+    /// Upper frequency of the mel and chroma analysis, Nyquist if `None`.
+    pub fmax: Option<f32>,
+    pub mel_scale: MelScale,
+    /// Fraction of spectral magnitude below the rolloff frequency.
+    pub rolloff_percent: f32,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            frame_len: 2048,
+            hop: 512,
+            n_mels: 40,
+            n_mfcc: 13,
+            fmin: 0.0,
+            fmax: None,
+            mel_scale: MelScale::Slaney,
+            rolloff_percent: 0.85,
+        }
+    }
+}
+
+/// `n_frames` rows of `dim` values, row-major.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct FeatureMatrix {
+    pub dim: usize,
+    pub data: Vec<f32>,
+}
+
+impl FeatureMatrix {
+    pub fn new(dim: usize) -> Self {
+        Self {
+            dim,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn n_frames(&self) -> usize {
+        self.data.len().checked_div(self.dim).unwrap_or_default()
+    }
+
+    /// Empty for a matrix without dimensions.
+    pub fn frame(&self, index: usize) -> &[f32] {
+        if self.dim == 0 {
+            return &[];
+        }
+        &self.data[index * self.dim..(index + 1) * self.dim]
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = &[f32]> {
+        self.data.chunks_exact(self.dim.max(1))
+    }
+}
+
+/// Frame `i` covers samples `[i * hop, i * hop + frame_len)` after `offset`,
+/// zero padded past the end of the audio.
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct Features {
+    pub config: FeatureConfig,
+    pub sample_rate: u32,
+    /// Track time of the first sample of frame 0.
+    pub offset: Duration,
+    /// Mel-frequency cepstral coefficients of the dB mel spectrum, `n_mfcc` per frame.
+    pub mfcc: FeatureMatrix,
+    /// Energy per pitch class starting at C, normalized to a maximum of 1 per frame.
+    pub chroma: FeatureMatrix,
+    /// Spectral centroid in Hz.
+    pub centroid: Vec<f32>,
+    /// Rolloff frequency in Hz.
+    pub rolloff: Vec<f32>,
+    /// L2 norm of the positive magnitude change from the previous frame.
+    pub flux: Vec<f32>,
+    /// Sign changes per sample.
+    pub zcr: Vec<f32>,
+    pub rms: Vec<f32>,
+}
+
+impl Features {
+    pub fn n_frames(&self) -> usize {
+        self.rms.len()
+    }
+
+    pub fn hop_duration(&self) -> Duration {
+        frames_to_duration(self.config.hop as u64, self.sample_rate)
+    }
+
+    /// Track time of the centre of frame `index`.
+    pub fn frame_time(&self, index: usize) -> Duration {
+        let center = index as u64 * self.config.hop as u64 + self.config.frame_len as u64 / 2;
+        self.offset + frames_to_duration(center, self.sample_rate)
+    }
+
+    /// Frame whose centre is closest to track time `time`.
+    pub fn frame_at(&self, time: Duration) -> Option<usize> {
+        let first_center = self.frame_time(0);
+        let hop = self.hop_duration().as_secs_f64();
+        let index = ((time.as_secs_f64() - first_center.as_secs_f64()) / hop).round();
+        (index >= 0.0 && (index as usize) < self.n_frames()).then_some(index as usize)
+    }
+}
+
+/// Computes [`Features`] frame by frame, see [`extract_features_from_stream`].
+pub struct FeatureExtractor {
+    sample_rate: u32,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    mel: MelFilterbank,
+    /// Orthonormal DCT-II basis, `n_mfcc` rows of `n_mels`.
+    dct: Vec<f32>,
+    /// Pitch class of every FFT bin inside the chroma range.
+    chroma_bins: Vec<Option<usize>>,
+    spectrum: Vec<Complex<f32>>,
+    magnitude: Vec<f32>,
+    prev_magnitude: Vec<f32>,
+    mel_frame: Vec<f32>,
+    features: Features,
+}
+
+impl FeatureExtractor {
+    pub fn new(sample_rate: u32, config: FeatureConfig) -> Result<Self> {
+        anyhow::ensure!(sample_rate > 0, "sample rate must be non-zero");
+        anyhow::ensure!(
+            config.frame_len > 1 && config.hop > 0,
+            "frame_len must be above 1 and hop non-zero"
+        );
+        anyhow::ensure!(
+            config.n_mfcc <= config.n_mels && config.n_mels > 0,
+            "n_mfcc must not exceed n_mels"
+        );
+
+        let n = config.frame_len;
+        let n_bins = n / 2 + 1;
+        let fft = FftPlanner::new().plan_fft_forward(n);
+
+        // Periodic Hann window.
+        let window = (0..n)
+            .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / n as f32).cos())
+            .collect();
+
+        let mel = MelFilterbank::new(
+            sample_rate,
+            n,
+            config.n_mels,
+            config.fmin,
+            config.fmax,
+            config.mel_scale,
+            true,
+        );
+
+        let n_mels = config.n_mels;
+        let dct = (0..config.n_mfcc)
+            .flat_map(|k| {
+                let scale = if k == 0 {
+                    (1.0 / n_mels as f32).sqrt()
+                } else {
+                    (2.0 / n_mels as f32).sqrt()
+                };
+                (0..n_mels).map(move |m| {
+                    scale
+                        * (std::f32::consts::PI * k as f32 * (m as f32 + 0.5) / n_mels as f32).cos()
+                })
+            })
+            .collect();
+
+        let bin_hz = sample_rate as f32 / n as f32;
+        let chroma_max = config.fmax.unwrap_or(sample_rate as f32 / 2.0);
+        // Below ~C1 a bin spans several semitones, so it says nothing about pitch class.
+        let chroma_min = config.fmin.max(32.7);
+        let chroma_bins = (0..n_bins)
+            .map(|bin| {
+                let f = bin as f32 * bin_hz;
+                (f >= chroma_min && f <= chroma_max).then(|| {
+                    let midi = 69.0 + 12.0 * (f / 440.0).log2();
+                    (midi.round() as i64).rem_euclid(12) as usize
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            sample_rate,
+            fft,
+            window,
+            mel,
+            dct,
+            chroma_bins,
+            spectrum: vec![Complex::default(); n],
+            magnitude: vec![0.0; n_bins],
+            prev_magnitude: Vec::new(),
+            mel_frame: vec![0.0; config.n_mels],
+            features: Features {
+                sample_rate,
+                offset: Duration::ZERO,
+                mfcc: FeatureMatrix::new(config.n_mfcc),
+                chroma: FeatureMatrix::new(12),
+                centroid: Vec::new(),
+                rolloff: Vec::new(),
+                flux: Vec::new(),
+                zcr: Vec::new(),
+                rms: Vec::new(),
+                config,
+            },
+        })
+    }
+
+    pub fn config(&self) -> &FeatureConfig {
+        &self.features.config
+    }
+
+    /// Adds one frame of `frame_len` samples, shorter frames are zero padded.
+    pub fn push_frame(&mut self, frame: &[f32]) {
+        let n = self.features.config.frame_len;
+        let frame = &frame[..frame.len().min(n)];
+
+        let energy: f32 = frame.iter().map(|s| s * s).sum();
+        self.features.rms.push((energy / n as f32).sqrt());
+
+        let crossings = frame
+            .windows(2)
+            .filter(|w| (w[0] >= 0.0) != (w[1] >= 0.0))
+            .count();
+        self.features.zcr.push(crossings as f32 / (n - 1) as f32);
+
+        for (i, c) in self.spectrum.iter_mut().enumerate() {
+            let sample = frame.get(i).copied().unwrap_or_default();
+            *c = Complex::new(sample * self.window[i], 0.0);
+        }
+        self.fft.process(&mut self.spectrum);
+        for (m, c) in self.magnitude.iter_mut().zip(&self.spectrum) {
+            *m = c.norm();
+        }
+
+        self.push_spectral_shape();
+        self.push_mfcc();
+        self.push_chroma();
+
+        std::mem::swap(&mut self.prev_magnitude, &mut self.magnitude);
+        self.magnitude.resize(self.prev_magnitude.len(), 0.0);
+    }
+
+    pub fn finish(mut self, offset: Duration) -> Features {
+        self.features.offset = offset;
+        self.features
+    }
+
+    fn push_spectral_shape(&mut self) {
+        let bin_hz = self.sample_rate as f32 / self.features.config.frame_len as f32;
+        let total: f32 = self.magnitude.iter().sum();
+
+        let centroid = if total > 0.0 {
+            self.magnitude
+                .iter()
+                .enumerate()
+                .map(|(bin, m)| bin as f32 * bin_hz * m)
+                .sum::<f32>()
+                / total
+        } else {
+            0.0
+        };
+        self.features.centroid.push(centroid);
+
+        let threshold = total * self.features.config.rolloff_percent;
+        let mut cumulative = 0.0;
+        let rolloff_bin = self
+            .magnitude
+            .iter()
+            .position(|m| {
+                cumulative += m;
+                cumulative >= threshold
+            })
+            .unwrap_or_default();
+        self.features.rolloff.push(rolloff_bin as f32 * bin_hz);
+
+        let flux = if self.prev_magnitude.is_empty() {
+            0.0
+        } else {
+            self.magnitude
+                .iter()
+                .zip(&self.prev_magnitude)
+                .map(|(m, p)| (m - p).max(0.0).powi(2))
+                .sum::<f32>()
+                .sqrt()
+        };
+        self.features.flux.push(flux);
+    }
+
+    fn push_mfcc(&mut self) {
+        let power: Vec<f32> = self.magnitude.iter().map(|m| m * m).collect();
+        self.mel.apply(&power, &mut self.mel_frame);
+        for value in &mut self.mel_frame {
+            *value = 10.0 * value.max(LOG_FLOOR).log10();
+        }
+
+        let n_mels = self.mel_frame.len();
+        for basis in self.dct.chunks_exact(n_mels) {
+            let coefficient = basis.iter().zip(&self.mel_frame).map(|(b, m)| b * m).sum();
+            self.features.mfcc.data.push(coefficient);
+        }
+    }
+
+    fn push_chroma(&mut self) {
+        let mut chroma = [0f32; 12];
+        for (class, m) in self.chroma_bins.iter().zip(&self.magnitude) {
+            if let Some(class) = class {
+                chroma[*class] += m * m;
+            }
+        }
+        let max = chroma.iter().copied().fold(0.0, f32::max);
+        if max > 0.0 {
+            chroma.iter_mut().for_each(|c| *c /= max);
+        }
+        self.features.chroma.data.extend_from_slice(&chroma);
+    }
+}
+
+/// Features of in-memory mono `samples`, `offset` being the track time of `samples[0]`.
+///
+/// Frames start every `hop` samples for as long as the start lies inside the
+/// audio, the same framing as [`crate::sound::FramedAudioStream`].
+pub fn compute_features(
+    samples: &[f32],
+    sample_rate: u32,
+    offset: Duration,
+    config: &FeatureConfig,
+) -> Result<Features> {
+    let mut extractor = FeatureExtractor::new(sample_rate, config.clone())?;
+    for start in (0..samples.len()).step_by(config.hop) {
+        let end = (start + config.frame_len).min(samples.len());
+        extractor.push_frame(&samples[start..end]);
+    }
+    Ok(extractor.finish(offset))
+}
+
+/// Decodes `path` (through the audio cache, if installed) and computes its features.
+pub fn extract_features(
+    path: impl AsRef<Path>,
+    decode_options: &DecodeOptions,
+    config: &FeatureConfig,
+) -> Result<Features> {
+    let path = path.as_ref();
+    let audio = decode_audio(path, decode_options)
+        .with_context(|| format!("Failed to decode: {}", path.display()))?;
+    compute_features(&audio.samples, audio.sample_rate, audio.offset, config)
+}
+
+/// Computes features while decoding, without holding the whole track in memory.
+pub fn extract_features_from_stream(
+    stream: AudioStream,
+    config: &FeatureConfig,
+) -> Result<Features> {
+    let offset = stream.start_time();
+    let mut extractor = FeatureExtractor::new(stream.sample_rate(), config.clone())?;
+    for frame in stream.framed(config.frame_len, config.hop) {
+        extractor.push_frame(&frame?.samples);
+    }
+    Ok(extractor.finish(offset))
+}
+
+pub fn save_features(features: &Features, path: impl AsRef<Path>) -> Result<()> {
+    CONTAINER.save(features, path)
+}
+
+pub fn load_features(path: impl AsRef<Path>) -> Result<Features> {
+    CONTAINER.load(path)
+}
+
+/// Level of an [`Features::rms`] value in dBFS, floored at -200 dB.
+pub(crate) fn rms_to_db(rms: f32) -> f32 {
+    20.0 * rms.max(1e-10).log10()
+}
+
+/// Mean of `values`, zero when empty.
+pub(crate) fn mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f32>() / values.len() as f32
+    }
+}
@@ -0,0 +1,259 @@
+//! Filterbanks that map linear FFT bins onto perceptual frequency scales.
+
+use anyhow::Result;
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+pub enum MelScale {
+    /// `2595 * log10(1 + f / 700)`.
+    Htk,
+    /// Linear below 1 kHz and logarithmic above, as librosa's default.
+    #[default]
+    Slaney,
+}
+
+impl MelScale {
+    pub fn hz_to_mel(&self, hz: f64) -> f64 {
+        match self {
+            MelScale::Htk => 2595.0 * (1.0 + hz / 700.0).log10(),
+            MelScale::Slaney => {
+                let min_log_hz = 1000.0;
+                let min_log_mel = min_log_hz / SLANEY_HZ_PER_MEL;
+                if hz >= min_log_hz {
+                    min_log_mel + (hz / min_log_hz).ln() / SLANEY_LOG_STEP
+                } else {
+                    hz / SLANEY_HZ_PER_MEL
+                }
+            }
+        }
+    }
+
+    pub fn mel_to_hz(&self, mel: f64) -> f64 {
+        match self {
+            MelScale::Htk => 700.0 * (10f64.powf(mel / 2595.0) - 1.0),
+            MelScale::Slaney => {
+                let min_log_hz = 1000.0;
+                let min_log_mel = min_log_hz / SLANEY_HZ_PER_MEL;
+                if mel >= min_log_mel {
+                    min_log_hz * (SLANEY_LOG_STEP * (mel - min_log_mel)).exp()
+                } else {
+                    mel * SLANEY_HZ_PER_MEL
+                }
+            }
+        }
+    }
+}
+
+const SLANEY_HZ_PER_MEL: f64 = 200.0 / 3.0;
+/// `ln(6.4) / 27`, the log step of the Slaney scale above 1 kHz.
+const SLANEY_LOG_STEP: f64 = 0.068_751_777_420_949_12;
+
+/// Triangular mel filters over the bins of an `n_fft` point real FFT.
+///
+/// Filters are stored sparsely as a start bin and the weights from there on.
+#[derive(Debug, Clone)]
+pub struct MelFilterbank {
+    filters: Vec<(usize, Vec<f32>)>,
+    centers: Vec<f32>,
+    n_bins: usize,
+}
+
+impl MelFilterbank {
+    /// `fmax` defaults to Nyquist. With `area_normalize` every filter is
+    /// scaled to unit area (librosa's `norm="slaney"`), otherwise filters
+    /// peak at 1.
+    pub fn new(
+        sample_rate: u32,
+        n_fft: usize,
+        n_mels: usize,
+        fmin: f32,
+        fmax: Option<f32>,
+        scale: MelScale,
+        area_normalize: bool,
+    ) -> Self {
+        let n_bins = n_fft / 2 + 1;
+        let nyquist = sample_rate as f64 / 2.0;
+        let fmax = fmax.map_or(nyquist, |f| (f as f64).min(nyquist));
+        let fmin = (fmin as f64).clamp(0.0, fmax);
+
+        let mel_min = scale.hz_to_mel(fmin);
+        let mel_max = scale.hz_to_mel(fmax);
+        let edges: Vec<f64> = (0..n_mels + 2)
+            .map(|i| {
+                scale.mel_to_hz(mel_min + (mel_max - mel_min) * i as f64 / (n_mels + 1) as f64)
+            })
+            .collect();
+        let bin_hz = sample_rate as f64 / n_fft as f64;
+
+        let filters = edges
+            .windows(3)
+            .map(|edge| {
+                let (lo, center, hi) = (edge[0], edge[1], edge[2]);
+                let norm = if area_normalize { 2.0 / (hi - lo) } else { 1.0 };
+
+                let first = ((lo / bin_hz).floor() as usize).min(n_bins);
+                let last = ((hi / bin_hz).ceil() as usize).min(n_bins - 1);
+                let weights = (first..=last.max(first))
+                    .map(|bin| {
+                        let f = bin as f64 * bin_hz;
+                        let up = (f - lo) / (center - lo);
+                        let down = (hi - f) / (hi - center);
+                        (up.min(down).max(0.0) * norm) as f32
+                    })
+                    .collect();
+                (first, weights)
+            })
+            .collect();
+
+        let centers = edges[1..=n_mels].iter().map(|&f| f as f32).collect();
+        Self {
+            filters,
+            centers,
+            n_bins,
+        }
+    }
+
+    pub fn n_mels(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Number of FFT bins the filters expect, `n_fft / 2 + 1`.
+    pub fn n_bins(&self) -> usize {
+        self.n_bins
+    }
+
+    /// Applies the filters to one spectrum frame (power or magnitude), writing `n_mels` values.
+    pub fn apply(&self, spectrum: &[f32], out: &mut [f32]) {
+        for ((start, weights), out) in self.filters.iter().zip(out.iter_mut()) {
+            *out = weights
+                .iter()
+                .zip(spectrum.iter().skip(*start))
+                .map(|(w, s)| w * s)
+                .sum();
+        }
+    }
+
+    /// Centre frequency of every filter in Hz.
+    pub fn center_frequencies(&self) -> &[f32] {
+        &self.centers
+    }
+}
+
+/// Spectral kernels of a constant-Q transform (Brown and Puckette), one
+/// per bin, geometrically spaced from `fmin`.
+///
+/// Bin `k` correlates a Hann-windowed sinusoid at `fmin * 2^(k / bins_per_octave)`
+/// lasting `Q` periods with the full complex FFT of an unwindowed frame of
+/// [`fft_len`](Self::fft_len) samples. Kernels are centred in the frame.
+#[derive(Debug, Clone)]
+pub struct ConstantQKernel {
+    /// Sparse `(bin, conj(K) / fft_len)` entries per CQT bin.
+    kernels: Vec<Vec<(usize, Complex<f32>)>>,
+    frequencies: Vec<f32>,
+    fft_len: usize,
+}
+
+impl ConstantQKernel {
+    /// Kernel entries below this fraction of a kernel's peak are dropped.
+    const SPARSITY: f32 = 0.0054;
+
+    /// `min_fft_len` is raised to fit the longest kernel, the one of `fmin`.
+    pub fn new(
+        sample_rate: u32,
+        fmin: f32,
+        bins_per_octave: usize,
+        n_bins: usize,
+        min_fft_len: usize,
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            fmin > 0.0 && bins_per_octave > 0 && n_bins > 0,
+            "constant-Q fmin, bins_per_octave and n_bins must be positive"
+        );
+        let sr = sample_rate as f64;
+        let frequencies: Vec<f64> = (0..n_bins)
+            .map(|k| fmin as f64 * 2f64.powf(k as f64 / bins_per_octave as f64))
+            .collect();
+        let fmax = frequencies[n_bins - 1];
+        anyhow::ensure!(
+            fmax < sr / 2.0,
+            "highest constant-Q bin at {fmax:.0} Hz is above Nyquist"
+        );
+
+        let q = 1.0 / (2f64.powf(1.0 / bins_per_octave as f64) - 1.0);
+        let lengths: Vec<usize> = frequencies
+            .iter()
+            .map(|f| (q * sr / f).ceil() as usize)
+            .collect();
+        let fft_len = lengths[0].max(min_fft_len).next_power_of_two();
+        let fft = FftPlanner::new().plan_fft_forward(fft_len);
+
+        let kernels = lengths
+            .iter()
+            .map(|&len| {
+                let mut kernel = vec![Complex::<f32>::default(); fft_len];
+                let offset = (fft_len - len) / 2;
+                for n in 0..len {
+                    let x = n as f64 / len as f64;
+                    let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * x).cos();
+                    let phase = 2.0 * std::f64::consts::PI * q * x;
+                    let value = Complex::from_polar(window / len as f64, phase);
+                    kernel[offset + n] = Complex::new(value.re as f32, value.im as f32);
+                }
+                fft.process(&mut kernel);
+
+                let peak = kernel.iter().map(|c| c.norm()).fold(0.0, f32::max);
+                kernel
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.norm() >= peak * Self::SPARSITY)
+                    .map(|(bin, c)| (bin, c.conj() / fft_len as f32))
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            kernels,
+            frequencies: frequencies.iter().map(|&f| f as f32).collect(),
+            fft_len,
+        })
+    }
+
+    /// Frame length the kernels expect.
+    pub fn fft_len(&self) -> usize {
+        self.fft_len
+    }
+
+    pub fn n_bins(&self) -> usize {
+        self.kernels.len()
+    }
+
+    /// Applies the kernels to the complex FFT of one frame, writing the
+    /// magnitude of every CQT bin, lowest first.
+    pub fn apply(&self, spectrum: &[Complex<f32>], out: &mut [f32]) {
+        for (kernel, out) in self.kernels.iter().zip(out.iter_mut()) {
+            *out = kernel
+                .iter()
+                .map(|&(bin, k)| spectrum[bin] * k)
+                .sum::<Complex<f32>>()
+                .norm();
+        }
+    }
+
+    /// Centre frequency of every bin in Hz.
+    pub fn center_frequencies(&self) -> &[f32] {
+        &self.frequencies
+    }
+}
@@ -0,0 +1,514 @@
+//! Audio fingerprints from spectral peak constellations, and an index that
+//! finds time ranges repeating across episodes, e.g. the season's opening.
+//!
+//! Prominent spectral peaks are paired up into hashes of both frequencies
+//! and their time distance. Two recordings of the same song share many
+//! hashes at one constant time offset, which is what the matcher votes on.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex;
+use serde::{Deserialize, Serialize};
+
+use crate::progress::{Stage, run_parallel};
+use crate::sound::resample::resample;
+use crate::sound::{DecodeOptions, decode_audio, frames_to_duration};
+
+const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
+
+/// Hashes pack two 10-bit bins and a 6-bit frame distance.
+const MAX_HASH_BIN: usize = 1 << 10;
+const MAX_HASH_DT: usize = 1 << 6;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct FingerprintConfig {
+    /// Audio is resampled to this rate first, most of the identifying peaks are below 5 kHz.
+    pub sample_rate: u32,
+    pub n_fft: usize,
+    pub hop: usize,
+    /// Peaks are searched in this many log-spaced bands between `fmin` and `fmax`.
+    pub n_bands: usize,
+    pub fmin: f32,
+    pub fmax: f32,
+    /// A peak must be the maximum of its band within this many frames on either side.
+    pub peak_neighborhood: usize,
+    /// Peaks quieter than this (dB relative to a full-scale sine) are ignored.
+    pub min_peak_db: f32,
+    /// Number of later peaks every peak is paired with.
+    pub fan_out: usize,
+    /// Frame distance range of paired peaks, the upper bound must be below 64.
+    pub target_zone: (usize, usize),
+}
+
+impl Default for FingerprintConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 11025,
+            n_fft: 1024,
+            hop: 256,
+            n_bands: 6,
+            fmin: 300.0,
+            fmax: 5000.0,
+            peak_neighborhood: 10,
+            min_peak_db: -60.0,
+            fan_out: 5,
+            target_zone: (1, 63),
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub struct FingerprintHash {
+    pub hash: u32,
+    /// Frame of the anchor peak.
+    pub frame: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct Fingerprint {
+    pub config: FingerprintConfig,
+    /// Track time of frame 0.
+    pub offset: Duration,
+    pub n_frames: u32,
+    /// Sorted by frame.
+    pub hashes: Vec<FingerprintHash>,
+}
+
+impl Fingerprint {
+    /// Track time of the start of `frame`.
+    pub fn frame_time(&self, frame: u32) -> Duration {
+        self.offset
+            + frames_to_duration(
+                frame as u64 * self.config.hop as u64,
+                self.config.sample_rate,
+            )
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.frame_time(self.n_frames).saturating_sub(self.offset)
+    }
+}
+
+/// Fingerprints in-memory mono `samples`, `offset` being the track time of `samples[0]`.
+pub fn fingerprint_samples(
+    samples: &[f32],
+    sample_rate: u32,
+    offset: Duration,
+    config: &FingerprintConfig,
+) -> Result<Fingerprint> {
+    anyhow::ensure!(sample_rate > 0, "sample rate must be non-zero");
+    anyhow::ensure!(
+        config.n_fft > 1 && config.n_fft / 2 < MAX_HASH_BIN && config.hop > 0,
+        "n_fft must be in 2..=2047 and hop non-zero"
+    );
+    anyhow::ensure!(
+        config.target_zone.0 > 0
+            && config.target_zone.0 <= config.target_zone.1
+            && config.target_zone.1 < MAX_HASH_DT,
+        "target zone must be a non-empty range within 1..64"
+    );
+
+    let resampled;
+    let samples = if sample_rate == config.sample_rate {
+        samples
+    } else {
+        resampled = resample(samples, sample_rate, config.sample_rate);
+        &resampled
+    };
+
+    let peaks = find_peaks(samples, config);
+    let n_frames = samples.len().div_ceil(config.hop) as u32;
+
+    let mut hashes = Vec::new();
+    for (i, &(frame, bin)) in peaks.iter().enumerate() {
+        let targets = peaks[i + 1..]
+            .iter()
+            .skip_while(|&&(f, _)| (f - frame) < config.target_zone.0 as u32)
+            .take_while(|&&(f, _)| (f - frame) <= config.target_zone.1 as u32)
+            .take(config.fan_out);
+        for &(target_frame, target_bin) in targets {
+            let dt = target_frame - frame;
+            hashes.push(FingerprintHash {
+                hash: (bin << 16) | (target_bin << 6) | dt,
+                frame,
+            });
+        }
+    }
+
+    Ok(Fingerprint {
+        config: config.clone(),
+        offset,
+        n_frames,
+        hashes,
+    })
+}
+
+/// Decodes `path` (through the audio cache, if installed) at the fingerprint's sample rate and fingerprints it.
+pub fn fingerprint_audio(
+    path: impl AsRef<Path>,
+    decode_options: &DecodeOptions,
+    config: &FingerprintConfig,
+) -> Result<Fingerprint> {
+    let path = path.as_ref();
+    let decode_options = DecodeOptions {
+        target_sample_rate: Some(config.sample_rate),
+        ..decode_options.clone()
+    };
+    let audio = decode_audio(path, &decode_options)
+        .with_context(|| format!("Failed to decode: {}", path.display()))?;
+    fingerprint_samples(&audio.samples, audio.sample_rate, audio.offset, config)
+}
+
+/// Spectral peaks as `(frame, bin)`, sorted by frame then bin.
+fn find_peaks(samples: &[f32], config: &FingerprintConfig) -> Vec<(u32, u32)> {
+    let n = config.n_fft;
+    let n_bins = n / 2 + 1;
+    let fft = FftPlanner::new().plan_fft_forward(n);
+    let window: Vec<f32> = (0..n)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / n as f32).cos())
+        .collect();
+    // A full-scale sine peaks at n / 4 with a Hann window.
+    let full_scale = n as f32 / 4.0;
+
+    let bin_hz = config.sample_rate as f32 / n as f32;
+    let fmax = config.fmax.min(config.sample_rate as f32 / 2.0);
+    let bands: Vec<(usize, usize)> = (0..config.n_bands)
+        .map(|b| {
+            let edge = |i: usize| {
+                let f = config.fmin * (fmax / config.fmin).powf(i as f32 / config.n_bands as f32);
+                ((f / bin_hz).round() as usize).min(n_bins)
+            };
+            (edge(b), edge(b + 1))
+        })
+        .filter(|(lo, hi)| hi > lo)
+        .collect();
+
+    // Strongest bin and its level per frame and band.
+    let mut band_max: Vec<Vec<(u32, f32)>> = vec![Vec::new(); bands.len()];
+    let mut spectrum = vec![Complex::default(); n];
+    for start in (0..samples.len()).step_by(config.hop) {
+        for (i, c) in spectrum.iter_mut().enumerate() {
+            let sample = samples.get(start + i).copied().unwrap_or_default();
+            *c = Complex::new(sample * window[i], 0.0);
+        }
+        fft.process(&mut spectrum);
+
+        for ((lo, hi), maxima) in bands.iter().zip(&mut band_max) {
+            let (bin, magnitude) =
+                (*lo..*hi)
+                    .map(|bin| (bin, spectrum[bin].norm()))
+                    .fold(
+                        (*lo, 0f32),
+                        |best, cur| if cur.1 > best.1 { cur } else { best },
+                    );
+            let db = 20.0 * (magnitude / full_scale).max(1e-10).log10();
+            maxima.push((bin as u32, db));
+        }
+    }
+
+    let mut peaks = Vec::new();
+    let reach = config.peak_neighborhood;
+    for maxima in &band_max {
+        for (frame, &(bin, db)) in maxima.iter().enumerate() {
+            if db < config.min_peak_db {
+                continue;
+            }
+            let lo = frame.saturating_sub(reach);
+            let hi = (frame + reach + 1).min(maxima.len());
+            if maxima[lo..frame].iter().all(|m| m.1 < db)
+                && maxima[frame + 1..hi].iter().all(|m| m.1 <= db)
+            {
+                peaks.push((frame as u32, bin));
+            }
+        }
+    }
+    peaks.sort_unstable();
+    peaks
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchConfig {
+    /// Fewest hashes agreeing on one offset for a range to count.
+    pub min_matches: usize,
+    /// Shortest matched range reported.
+    pub min_duration: Duration,
+    /// Matches further apart than this split a range in two.
+    pub max_gap: Duration,
+    /// Offsets within this many frames are treated as one.
+    pub offset_tolerance: u32,
+    /// Hashes occurring more often than this in the index are too common to be evidence.
+    pub max_hash_occurrences: usize,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            min_matches: 20,
+            min_duration: Duration::from_secs(20),
+            max_gap: Duration::from_secs(3),
+            offset_tolerance: 1,
+            max_hash_occurrences: 200,
+        }
+    }
+}
+
+/// A stretch of the query that also plays in a matched recording.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MatchedRange {
+    /// Track times in the query.
+    pub query_start: Duration,
+    pub query_end: Duration,
+    /// Track times in the matched recording.
+    pub matched_start: Duration,
+    pub matched_end: Duration,
+    /// Matched minus query track time, in seconds.
+    pub offset: f64,
+    pub matches: usize,
+    /// Fraction of the query's hashes in the range that matched.
+    pub score: f32,
+}
+
+/// A range of episode `a` that repeats in episode `b`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RepeatedSegment {
+    pub a: usize,
+    pub b: usize,
+    pub range: MatchedRange,
+}
+
+/// Fingerprints of a set of episodes with an inverted hash table.
+#[derive(Debug, Default)]
+pub struct FingerprintIndex {
+    episodes: Vec<(PathBuf, Fingerprint)>,
+    /// Episode and anchor frame of every hash.
+    table: HashMap<u32, Vec<(u32, u32)>>,
+}
+
+impl FingerprintIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fingerprints every file in parallel, failed files are logged and left out.
+    /// Progress and cancellation come from `decode_options.control`.
+    pub fn build(
+        paths: &[PathBuf],
+        decode_options: &DecodeOptions,
+        config: &FingerprintConfig,
+    ) -> Result<Self> {
+        let results = run_parallel(
+            paths,
+            &decode_options.control,
+            Stage::Fingerprinting,
+            |path| fingerprint_audio(path, decode_options, config),
+        )?;
+
+        // Episode ids follow the order of `paths` regardless of which worker finished first.
+        let mut index = Self::new();
+        for (path, result) in paths.iter().zip(results) {
+            match result {
+                Ok(fingerprint) => {
+                    index.add(path.clone(), fingerprint);
+                }
+                Err(e) => log::error!("Fingerprinting failed: {e:?}"),
+            }
+        }
+        Ok(index)
+    }
+
+    /// Adds an episode and returns its id.
+    pub fn add(&mut self, path: PathBuf, fingerprint: Fingerprint) -> usize {
+        let id = self.episodes.len();
+        for h in &fingerprint.hashes {
+            self.table
+                .entry(h.hash)
+                .or_default()
+                .push((id as u32, h.frame));
+        }
+        self.episodes.push((path, fingerprint));
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.episodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.episodes.is_empty()
+    }
+
+    pub fn path(&self, id: usize) -> &Path {
+        &self.episodes[id].0
+    }
+
+    pub fn fingerprint(&self, id: usize) -> &Fingerprint {
+        &self.episodes[id].1
+    }
+
+    /// Ranges of `query` found in the indexed episodes, as `(episode, range)`.
+    pub fn query(&self, query: &Fingerprint, config: &MatchConfig) -> Vec<(usize, MatchedRange)> {
+        self.matches(query, |_| true, config)
+    }
+
+    /// Every range that plays in more than one episode, once per episode pair.
+    pub fn find_repeats(&self, config: &MatchConfig) -> Vec<RepeatedSegment> {
+        (0..self.len())
+            .flat_map(|a| {
+                self.matches(&self.episodes[a].1, |b| b > a, config)
+                    .into_iter()
+                    .map(move |(b, range)| RepeatedSegment { a, b, range })
+            })
+            .collect()
+    }
+
+    fn matches(
+        &self,
+        query: &Fingerprint,
+        include: impl Fn(usize) -> bool,
+        config: &MatchConfig,
+    ) -> Vec<(usize, MatchedRange)> {
+        // Query anchor frames voting for every (episode, frame offset).
+        let mut votes: HashMap<(u32, i64), Vec<u32>> = HashMap::new();
+        for h in &query.hashes {
+            let Some(occurrences) = self.table.get(&h.hash) else {
+                continue;
+            };
+            if occurrences.len() > config.max_hash_occurrences {
+                continue;
+            }
+            for &(episode, frame) in occurrences {
+                if include(episode as usize) {
+                    votes
+                        .entry((episode, frame as i64 - h.frame as i64))
+                        .or_default()
+                        .push(h.frame);
+                }
+            }
+        }
+
+        let mut by_episode: HashMap<u32, Vec<(i64, usize)>> = HashMap::new();
+        for (&(episode, offset), frames) in &votes {
+            by_episode
+                .entry(episode)
+                .or_default()
+                .push((offset, frames.len()));
+        }
+
+        let mut results = Vec::new();
+        let mut episodes: Vec<u32> = by_episode.keys().copied().collect();
+        episodes.sort_unstable();
+        for episode in episodes {
+            let mut offsets = by_episode.remove(&episode).unwrap();
+            // Strongest offsets first, they absorb their jittered neighbours.
+            offsets.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+            let mut used = HashSet::new();
+            for (offset, count) in offsets {
+                if count < config.min_matches || used.contains(&offset) {
+                    continue;
+                }
+                let tolerance = config.offset_tolerance as i64;
+                let mut frames = Vec::new();
+                for o in offset - tolerance..=offset + tolerance {
+                    if used.insert(o)
+                        && let Some(f) = votes.get(&(episode, o))
+                    {
+                        frames.extend_from_slice(f);
+                    }
+                }
+                frames.sort_unstable();
+
+                let matched = &self.episodes[episode as usize].1;
+                for range in split_runs(query, &frames, config) {
+                    let (start, end, matches) = range;
+                    let shift = |frame: u32| (frame as i64 + offset).max(0) as u32;
+                    let total = query
+                        .hashes
+                        .iter()
+                        .filter(|h| (start..=end).contains(&h.frame))
+                        .count();
+                    let query_start = query.frame_time(start);
+                    let matched_start = matched.frame_time(shift(start));
+                    results.push((
+                        episode as usize,
+                        MatchedRange {
+                            query_start,
+                            query_end: query.frame_time(end + 1),
+                            matched_start,
+                            matched_end: matched.frame_time(shift(end) + 1),
+                            offset: matched_start.as_secs_f64() - query_start.as_secs_f64(),
+                            matches,
+                            score: matches as f32 / total.max(1) as f32,
+                        },
+                    ));
+                }
+            }
+        }
+        results
+    }
+}
+
+/// Splits sorted matched query frames wherever they are more than `max_gap`
+/// apart, keeping runs that pass the match count and duration limits, as
+/// `(first frame, last frame, matches)`.
+fn split_runs(query: &Fingerprint, frames: &[u32], config: &MatchConfig) -> Vec<(u32, u32, usize)> {
+    let hop = frames_to_duration(query.config.hop as u64, query.config.sample_rate);
+    let max_gap = (config.max_gap.as_secs_f64() / hop.as_secs_f64().max(f64::MIN_POSITIVE)) as u32;
+    let min_frames =
+        (config.min_duration.as_secs_f64() / hop.as_secs_f64().max(f64::MIN_POSITIVE)) as u32;
+
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for i in 1..=frames.len() {
+        if i < frames.len() && frames[i] - frames[i - 1] <= max_gap {
+            continue;
+        }
+        let run = &frames[start..i];
+        if let (Some(&first), Some(&last)) = (run.first(), run.last())
+            && run.len() >= config.min_matches
+            && last - first >= min_frames
+        {
+            runs.push((first, last, run.len()));
+        }
+        start = i;
+    }
+    runs
+}
+
+/// Ranges of `query` that also play in `reference`, e.g. a labeled opening
+/// clip against a whole episode.
+pub fn match_fingerprints(
+    query: &Fingerprint,
+    reference: &Fingerprint,
+    config: &MatchConfig,
+) -> Vec<MatchedRange> {
+    let mut index = FingerprintIndex::new();
+    index.add(PathBuf::new(), reference.clone());
+    index
+        .query(query, config)
+        .into_iter()
+        .map(|(_, range)| range)
+        .collect()
+}
+
+pub fn save_fingerprint(fingerprint: &Fingerprint, path: impl AsRef<Path>) -> Result<()> {
+    let bytes = bincode::encode_to_vec(fingerprint, BINCODE_CONFIG)?;
+    let mut file = File::create(path.as_ref())
+        .with_context(|| format!("Failed to create file at {}", path.as_ref().display()))?;
+    file.write_all(&bytes)?;
+    Ok(())
+}
+
+pub fn load_fingerprint(path: impl AsRef<Path>) -> Result<Fingerprint> {
+    let bytes = std::fs::read(&path).with_context(|| format!("{}", path.as_ref().display()))?;
+    let (fingerprint, _) = bincode::decode_from_slice(&bytes, BINCODE_CONFIG)?;
+    Ok(fingerprint)
+}
@@ -17,11 +17,28 @@
 */
 
 pub mod ai_labels;
+pub mod augment;
+pub mod boundary;
+pub mod cache;
 pub mod chapters;
+pub mod consistency;
+mod container;
+pub mod dataset;
+pub mod ebml;
+pub mod export;
+pub mod features;
 pub mod file;
+pub mod filterbank;
+pub mod fingerprint;
+pub mod hash;
 pub mod mkv;
+pub mod progress;
+pub mod propagate;
+pub mod pyramid;
+pub mod segment;
 pub mod sound;
 pub mod spectrogram;
+pub mod stft;
 pub mod temp;
 pub mod utils;
 
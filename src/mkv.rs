@@ -1,10 +1,12 @@
 use std::{
+    io::{Read, Seek},
     path::{Path, PathBuf},
     time::Duration,
 };
 
 use crate::{
     chapters::{ChapterAtom, VideoMetadata},
+    ebml::{self, ControlFlow},
     file::list_dir,
     utils::list_dir_with_kind_has_chapters_split,
 };
@@ -83,6 +85,74 @@ fn parse_time(s: &str) -> Option<Duration> {
     Some(Duration::new(hours * 3600 + minutes * 60 + seconds, nanos))
 }
 
+const ID_TRACKS: u32 = 0x1654_AE6B;
+const ID_TRACK_ENTRY: u32 = 0xAE;
+const ID_TRACK_NUMBER: u32 = 0xD7;
+const ID_CODEC_ID: u32 = 0x86;
+const ID_CODEC_DELAY: u32 = 0x56AA;
+const ID_SEEK_PRE_ROLL: u32 = 0x56BB;
+
+/// Timing fields of a Matroska track that symphonia's demuxer does not expose.
+#[derive(Debug, Clone, Default)]
+pub struct MkvTrackTiming {
+    /// `TrackNumber`, the same value symphonia uses as track id.
+    pub number: u64,
+    pub codec_id: String,
+    /// Decoder output to discard before the first real sample (`CodecDelay`).
+    /// Block timestamps must have this subtracted to get presentation time.
+    pub codec_delay: Duration,
+    /// Audio that has to be decoded and discarded after a seek (`SeekPreRoll`).
+    pub seek_pre_roll: Duration,
+}
+
+/// Reads the `Tracks` element of a Matroska file.
+pub fn read_track_timings<R: Read + Seek>(reader: &mut R) -> Result<Vec<MkvTrackTiming>> {
+    let segment = ebml::seek_to_segment(reader)?;
+
+    let mut timings = Vec::new();
+    ebml::for_each_child(reader, segment.data_end(), |reader, header| {
+        match header.id {
+            ID_TRACKS => {
+                ebml::for_each_child(reader, header.data_end(), |reader, entry| {
+                    if entry.id == ID_TRACK_ENTRY {
+                        timings.push(read_track_entry(reader, entry)?);
+                    }
+                    Ok(ControlFlow::Continue)
+                })?;
+                Ok(ControlFlow::Break)
+            }
+            // Tracks always precede the media data, no point scanning clusters.
+            ebml::ID_CLUSTER => Ok(ControlFlow::Break),
+            _ => Ok(ControlFlow::Continue),
+        }
+    })?;
+
+    Ok(timings)
+}
+
+fn read_track_entry<R: Read + Seek>(
+    reader: &mut R,
+    entry: &ebml::ElementHeader,
+) -> std::io::Result<MkvTrackTiming> {
+    let mut timing = MkvTrackTiming::default();
+    ebml::for_each_child(reader, entry.data_end(), |reader, field| {
+        let size = field.size.unwrap_or_default();
+        match field.id {
+            ID_TRACK_NUMBER => timing.number = ebml::read_uint(reader, size)?,
+            ID_CODEC_ID => timing.codec_id = ebml::read_string(reader, size)?,
+            ID_CODEC_DELAY => {
+                timing.codec_delay = Duration::from_nanos(ebml::read_uint(reader, size)?)
+            }
+            ID_SEEK_PRE_ROLL => {
+                timing.seek_pre_roll = Duration::from_nanos(ebml::read_uint(reader, size)?)
+            }
+            _ => {}
+        }
+        Ok(ControlFlow::Continue)
+    })?;
+    Ok(timing)
+}
+
 // ffprobe -select_streams v -show_frames -show_entries frame=pkt_pts_time -of csv input.mkv
 
 pub fn process_mkv_file(entry: &EntryKind) -> Result<MkvMetadata> {
@@ -0,0 +1,246 @@
+//! Progress reporting and cancellation for long running decode and batch jobs.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// Looking for files with chapters.
+    Scanning,
+    /// Writing label files.
+    Labeling,
+    Decoding,
+    Spectrogram,
+    Exporting,
+    Fingerprinting,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Scanning => "Scanning",
+            Stage::Labeling => "Labeling",
+            Stage::Decoding => "Decoding",
+            Stage::Spectrogram => "Spectrogram",
+            Stage::Exporting => "Exporting",
+            Stage::Fingerprinting => "Fingerprinting",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Amount {
+    /// Media time, e.g. how much of a track has been decoded.
+    Time(Duration),
+    Bytes(u64),
+    /// Files or entries.
+    Items(u64),
+}
+
+impl Amount {
+    fn as_f64(&self) -> f64 {
+        match self {
+            Amount::Time(t) => t.as_secs_f64(),
+            Amount::Bytes(n) | Amount::Items(n) => *n as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    pub stage: Stage,
+    /// File being worked on, `None` for whole-batch updates.
+    pub file: Option<&'a Path>,
+    pub processed: Amount,
+    /// `None` when the total is not known up front.
+    pub total: Option<Amount>,
+}
+
+impl Progress<'_> {
+    /// Completed fraction in `[0, 1]`, if the total is known and of the same unit.
+    pub fn fraction(&self) -> Option<f64> {
+        let total = self.total?;
+        if std::mem::discriminant(&total) != std::mem::discriminant(&self.processed) {
+            return None;
+        }
+        let total = total.as_f64();
+        (total > 0.0).then(|| (self.processed.as_f64() / total).clamp(0.0, 1.0))
+    }
+}
+
+/// Receives progress updates, possibly from several worker threads at once.
+pub trait ProgressReporter: Send + Sync {
+    fn report(&self, progress: &Progress);
+}
+
+impl<F: Fn(&Progress) + Send + Sync> ProgressReporter for F {
+    fn report(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Logs every 10% step per file and stage.
+#[derive(Default)]
+pub struct LogProgress {
+    last_step: Mutex<HashMap<(Stage, Option<PathBuf>), u32>>,
+}
+
+impl ProgressReporter for LogProgress {
+    fn report(&self, progress: &Progress) {
+        let Some(fraction) = progress.fraction() else {
+            return;
+        };
+        let step = (fraction * 10.0) as u32;
+
+        let key = (progress.stage, progress.file.map(Path::to_path_buf));
+        let mut last_step = self.last_step.lock().unwrap();
+        let last = last_step.entry(key).or_default();
+        if step > *last {
+            *last = step;
+            match progress.file {
+                Some(file) => log::info!("[{}%] {} {}", step * 10, progress.stage, file.display()),
+                None => log::info!("[{}%] {}", step * 10, progress.stage),
+            }
+        }
+    }
+}
+
+/// Shared flag to ask running jobs to stop. Clones observe the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Returned by jobs that stopped because their [`CancellationToken`] was cancelled.
+///
+/// Batch functions return it inside `anyhow::Error`, check with `e.is::<Cancelled>()`.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("operation cancelled")]
+pub struct Cancelled;
+
+/// Progress reporter and cancellation token handed to a job.
+#[derive(Clone, Default)]
+pub struct JobControl {
+    pub progress: Option<Arc<dyn ProgressReporter>>,
+    pub cancel: CancellationToken,
+}
+
+impl fmt::Debug for JobControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobControl")
+            .field("progress", &self.progress.is_some())
+            .field("cancel", &self.cancel)
+            .finish()
+    }
+}
+
+impl JobControl {
+    pub fn new(progress: impl ProgressReporter + 'static, cancel: CancellationToken) -> Self {
+        Self {
+            progress: Some(Arc::new(progress)),
+            cancel,
+        }
+    }
+
+    pub fn with_progress(progress: impl ProgressReporter + 'static) -> Self {
+        Self::new(progress, CancellationToken::default())
+    }
+
+    pub fn with_cancel(cancel: CancellationToken) -> Self {
+        Self {
+            progress: None,
+            cancel,
+        }
+    }
+
+    pub fn report(&self, progress: Progress) {
+        if let Some(reporter) = &self.progress {
+            reporter.report(&progress);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// `Err(Cancelled)` once the job has been cancelled.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Runs `job` on every item with one worker thread per core and returns the
+/// results in the order of `items`.
+///
+/// Every finished item is reported to `control` under `stage`. Once
+/// cancelled no further items are started and [`Cancelled`] is returned.
+pub fn run_parallel<T, R>(
+    items: &[T],
+    control: &JobControl,
+    stage: Stage,
+    job: impl Fn(&T) -> R + Sync,
+) -> Result<Vec<R>, Cancelled>
+where
+    T: AsRef<Path> + Sync,
+    R: Send,
+{
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(items.len().max(1));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(index) else {
+                        break;
+                    };
+                    if control.is_cancelled() {
+                        break;
+                    }
+
+                    let result = job(item);
+                    control.report(Progress {
+                        stage,
+                        file: Some(item.as_ref()),
+                        processed: Amount::Items(done.fetch_add(1, Ordering::Relaxed) as u64 + 1),
+                        total: Some(Amount::Items(items.len() as u64)),
+                    });
+                    results.lock().unwrap().push((index, result));
+                }
+            });
+        }
+    });
+
+    control.check()?;
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
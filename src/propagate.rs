@@ -0,0 +1,294 @@
+//! Labels for files without chapters, inferred by finding the opening of a
+//! labeled sibling episode in their audio.
+//!
+//! Siblings are labels of files in the same directory or with the same
+//! parsed series name. The labeled opening is fingerprinted and matched
+//! against the whole unlabeled episode; the matched offset shifts the
+//! sibling's opening times onto the new file.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::ai_labels::{
+    LabelProvenance, ZAOAI_LABEL_VERSION, ZaoaiLabel, ZaoaiLabelsLoader, write_label,
+};
+use crate::chapters::VideoMetadata;
+use crate::file::EntryKind;
+use crate::fingerprint::{
+    Fingerprint, FingerprintConfig, MatchConfig, fingerprint_audio, match_fingerprints,
+};
+use crate::mkv::process_mkv_file;
+use crate::progress::{Stage, run_parallel};
+use crate::sound::{DecodeOptions, TimeRange};
+use crate::utils::{ListDirSplit, series_name};
+
+#[derive(Debug, Clone)]
+pub struct PropagationOptions {
+    pub decode: DecodeOptions,
+    pub fingerprint: FingerprintConfig,
+    pub matching: MatchConfig,
+    /// Siblings tried per file, same-directory ones first.
+    pub max_references: usize,
+    /// Labels below this confidence are not written.
+    pub min_confidence: f32,
+}
+
+impl Default for PropagationOptions {
+    fn default() -> Self {
+        Self {
+            decode: DecodeOptions::default(),
+            fingerprint: FingerprintConfig::default(),
+            matching: MatchConfig::default(),
+            max_references: 3,
+            min_confidence: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PropagationSummary {
+    pub written: Vec<PathBuf>,
+    /// Files without siblings or without a confident match.
+    pub unmatched: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// Labels among `labels` that can serve as references for `path`, best first.
+///
+/// Only chapter-derived labels with an opening qualify, so errors of
+/// propagated labels do not spread further.
+pub fn sibling_labels<'a>(path: &Path, labels: &'a [ZaoaiLabel]) -> Vec<&'a ZaoaiLabel> {
+    let series = series_name(path);
+    let mut siblings: Vec<(bool, &ZaoaiLabel)> = labels
+        .iter()
+        .filter(|l| l.provenance == LabelProvenance::Chapters && l.path != path)
+        .filter(|l| l.opening_start_time.is_some() && l.opening_end_time.is_some())
+        .filter_map(|l| {
+            let same_dir = l.path.parent() == path.parent();
+            let same_series = series.is_some() && series_name(&l.path) == series;
+            (same_dir || same_series).then_some((same_dir, l))
+        })
+        .collect();
+    siblings.sort_by_key(|(same_dir, l)| (!same_dir, l.path.clone()));
+    siblings.into_iter().map(|(_, l)| l).collect()
+}
+
+/// Aligns the opening of every reference against `fingerprint` and returns
+/// the best inferred `(start, end)`, the reference used and its confidence.
+///
+/// The confidence is the fraction of the reference opening found in the file.
+pub fn align_opening(
+    fingerprint: &Fingerprint,
+    references: &[(&ZaoaiLabel, Arc<Fingerprint>)],
+    matching: &MatchConfig,
+) -> Option<(Duration, Duration, PathBuf, f32)> {
+    references
+        .iter()
+        .filter_map(|(label, opening)| {
+            let start = label.opening_start_time?;
+            let end = label.opening_end_time?;
+            let opening_secs = end.saturating_sub(start).as_secs_f64();
+
+            let best = match_fingerprints(opening, fingerprint, matching)
+                .into_iter()
+                .max_by_key(|range| range.matches)?;
+            let covered = best
+                .query_end
+                .saturating_sub(best.query_start)
+                .as_secs_f64();
+            let confidence = (covered / opening_secs.max(f64::MIN_POSITIVE)).min(1.0) as f32;
+
+            let shift =
+                |t: Duration| Duration::from_secs_f64((t.as_secs_f64() + best.offset).max(0.0));
+            Some((shift(start), shift(end), label.path.clone(), confidence))
+        })
+        .max_by(|a, b| a.3.total_cmp(&b.3))
+}
+
+/// Writes labels into `label_dir` for the video files of `list_dir_split`
+/// without chapters, aligned against the labels already in `label_dir`.
+/// Other files, e.g. subtitle sidecars, are left out.
+///
+/// Progress and cancellation come from `options.decode.control`.
+pub fn propagate_labels(
+    list_dir_split: &ListDirSplit,
+    label_dir: &Path,
+    options: &PropagationOptions,
+) -> Result<PropagationSummary> {
+    let loader = ZaoaiLabelsLoader::new(label_dir)?;
+    let labels = loader.load_zaoai_labels()?;
+    let files: Vec<&PathBuf> = list_dir_split
+        .without_chapters
+        .iter()
+        .filter_map(|e| match e {
+            // Same check as the chapter scan, which only looks into Matroska files.
+            EntryKind::File(path) if path.extension().is_some_and(|ext| ext == "mkv") => Some(path),
+            _ => None,
+        })
+        .collect();
+
+    let openings = Mutex::new(HashMap::new());
+    let results = run_parallel(&files, &options.decode.control, Stage::Labeling, |path| {
+        propagate_label(
+            path,
+            &list_dir_split.path_source,
+            label_dir,
+            &labels,
+            &openings,
+            options,
+        )
+    })?;
+
+    let mut summary = PropagationSummary::default();
+    for (path, result) in files.into_iter().zip(results) {
+        match result {
+            Ok(Some(label_path)) => summary.written.push(label_path),
+            Ok(None) => summary.unmatched.push(path.clone()),
+            Err(e) => {
+                log::error!("Label propagation failed for {}: {e:?}", path.display());
+                summary.failed.push((path.clone(), e));
+            }
+        }
+    }
+    Ok(summary)
+}
+
+fn propagate_label(
+    path: &Path,
+    path_source: &Path,
+    label_dir: &Path,
+    labels: &[ZaoaiLabel],
+    openings: &Mutex<HashMap<PathBuf, Arc<Fingerprint>>>,
+    options: &PropagationOptions,
+) -> Result<Option<PathBuf>> {
+    let siblings = sibling_labels(path, labels);
+    if siblings.is_empty() {
+        log::info!("No labeled siblings for {}", path.display());
+        return Ok(None);
+    }
+
+    let mut references = Vec::new();
+    for label in siblings.into_iter().take(options.max_references) {
+        match opening_fingerprint(label, openings, options) {
+            Ok(fingerprint) => references.push((label, fingerprint)),
+            Err(e) => log::warn!("Skipping reference {}: {e:?}", label.path.display()),
+        }
+    }
+
+    let fingerprint = fingerprint_audio(path, &options.decode, &options.fingerprint)?;
+    let Some((start, end, from, confidence)) =
+        align_opening(&fingerprint, &references, &options.matching)
+    else {
+        log::info!("No opening match for {}", path.display());
+        return Ok(None);
+    };
+    if confidence < options.min_confidence {
+        log::info!(
+            "Opening match for {} below confidence ({confidence:.2})",
+            path.display()
+        );
+        return Ok(None);
+    }
+
+    let metadata: VideoMetadata = process_mkv_file(&EntryKind::File(path.to_path_buf()))
+        .with_context(|| format!("Failed to read metadata of {}", path.display()))?
+        .into();
+    let total_secs = metadata.duration.as_secs_f64();
+
+    let label = ZaoaiLabel {
+        path: path.to_path_buf(),
+        path_source: path_source.to_path_buf(),
+        metadata,
+        version: ZAOAI_LABEL_VERSION,
+        opening_start_time: Some(start),
+        opening_end_time: Some(end),
+        opening_start_normalized: Some(start.as_secs_f64() / total_secs),
+        opening_end_normalized: Some(end.as_secs_f64() / total_secs),
+        provenance: LabelProvenance::Propagated { from, confidence },
+        ..Default::default()
+    };
+    write_label(&label, label_dir).map(Some)
+}
+
+/// Fingerprint of only the labeled opening of `label`.
+pub fn fingerprint_opening(
+    label: &ZaoaiLabel,
+    decode_options: &DecodeOptions,
+    config: &FingerprintConfig,
+) -> Result<Fingerprint> {
+    let (Some(start), Some(end)) = (label.opening_start_time, label.opening_end_time) else {
+        anyhow::bail!("label of {} has no opening", label.path.display());
+    };
+    let decode_options = DecodeOptions {
+        range: Some(TimeRange::new(start, end)),
+        ..decode_options.clone()
+    };
+    fingerprint_audio(&label.path, &decode_options, config)
+}
+
+/// [`fingerprint_opening`], computed once per reference.
+fn opening_fingerprint(
+    label: &ZaoaiLabel,
+    openings: &Mutex<HashMap<PathBuf, Arc<Fingerprint>>>,
+    options: &PropagationOptions,
+) -> Result<Arc<Fingerprint>> {
+    if let Some(fingerprint) = openings.lock().unwrap().get(&label.path) {
+        return Ok(fingerprint.clone());
+    }
+
+    let fingerprint = Arc::new(fingerprint_opening(
+        label,
+        &options.decode,
+        &options.fingerprint,
+    )?);
+    openings
+        .lock()
+        .unwrap()
+        .insert(label.path.clone(), fingerprint.clone());
+    Ok(fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn sidecar_files_are_not_fingerprinted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (videos, label_dir) = (dir.path().join("videos"), dir.path().join("labels"));
+        fs::create_dir_all(&label_dir).unwrap();
+        let sibling = ZaoaiLabel {
+            path: videos.join("Show - 01.mkv"),
+            opening_start_time: Some(Duration::from_secs(60)),
+            opening_end_time: Some(Duration::from_secs(150)),
+            ..Default::default()
+        };
+        fs::write(
+            label_dir.join("Show - 01.zlbl"),
+            serde_json::to_string(&sibling).unwrap(),
+        )
+        .unwrap();
+
+        let episode = videos.join("Show - 02.mkv");
+        let split = ListDirSplit {
+            path_source: videos.clone(),
+            without_chapters: vec![
+                EntryKind::File(episode.clone()),
+                EntryKind::File(videos.join("Show - 02.ass")),
+                EntryKind::File(videos.join("Show - 02.nfo")),
+            ],
+            ..Default::default()
+        };
+
+        // The episode does not exist, so it fails, but it is the only file tried.
+        let summary = propagate_labels(&split, &label_dir, &Default::default()).unwrap();
+        assert!(summary.written.is_empty() && summary.unmatched.is_empty());
+        let failed: Vec<&PathBuf> = summary.failed.iter().map(|(path, _)| path).collect();
+        assert_eq!(failed, [&episode]);
+    }
+}
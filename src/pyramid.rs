@@ -0,0 +1,169 @@
+//! Multi-resolution spectrograms for coarse-to-fine boundary detection.
+//!
+//! A pyramid holds a low-resolution view of the whole episode, enough to
+//! find roughly where the opening is, and high-resolution crops centred on
+//! the section boundaries for sub-second accuracy. Crops come from labeled
+//! boundaries or from boundaries predicted on the coarse level.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ai_labels::ZaoaiLabel;
+use crate::container::Container;
+use crate::spectrogram::ZaoSpectrogram;
+use crate::spectrogram::columns::TimeGrid;
+
+/// Bumped whenever [`SpectrogramPyramid`] changes incompatibly.
+pub const PYRAMID_FORMAT_VERSION: u32 = 1;
+
+const CONTAINER: Container = Container {
+    magic: b"ZPYR",
+    version: PYRAMID_FORMAT_VERSION,
+    name: "pyramid",
+};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub enum SectionBoundary {
+    OpeningStart,
+    OpeningEnd,
+    EndingStart,
+    EndingEnd,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PyramidConfig {
+    /// Grid of the full-episode level.
+    pub coarse: TimeGrid,
+    /// Time resolution of the crops.
+    pub fine_seconds_per_column: f64,
+    /// Time on each side of a boundary in its crop.
+    pub context: Duration,
+}
+
+impl Default for PyramidConfig {
+    fn default() -> Self {
+        Self {
+            coarse: TimeGrid {
+                seconds_per_column: 2.0,
+                columns: Some(900),
+                pad_value: None,
+            },
+            fine_seconds_per_column: 0.02,
+            context: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode)]
+pub struct PyramidCrop {
+    pub boundary: SectionBoundary,
+    /// Boundary time from the start of the episode spectrogram.
+    pub time: Duration,
+    /// Time of the crop's first column. Crops near the start or end are
+    /// shifted to stay inside the episode, the boundary is then off centre.
+    pub offset: Duration,
+    pub spectrogram: ZaoSpectrogram,
+}
+
+#[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode)]
+pub struct SpectrogramPyramid {
+    /// Full episode, starting at offset zero.
+    pub coarse: ZaoSpectrogram,
+    pub crops: Vec<PyramidCrop>,
+}
+
+impl SpectrogramPyramid {
+    pub fn crop(&self, boundary: SectionBoundary) -> Option<&PyramidCrop> {
+        self.crops.iter().find(|c| c.boundary == boundary)
+    }
+}
+
+/// Opening boundaries of `label` relative to its window, if windowed.
+pub fn label_boundaries(label: &ZaoaiLabel) -> Vec<(SectionBoundary, Duration)> {
+    let origin = label.window.map_or(Duration::ZERO, |w| w.start);
+    [
+        (SectionBoundary::OpeningStart, label.opening_start_time),
+        (SectionBoundary::OpeningEnd, label.opening_end_time),
+    ]
+    .into_iter()
+    .filter_map(|(boundary, time)| Some((boundary, time?.saturating_sub(origin))))
+    .collect()
+}
+
+/// Builds a pyramid from a high-resolution episode `spectrogram`, with a
+/// crop for every boundary in `boundaries`.
+pub fn build_pyramid(
+    spectrogram: &ZaoSpectrogram,
+    boundaries: &[(SectionBoundary, Duration)],
+    config: &PyramidConfig,
+) -> Result<SpectrogramPyramid> {
+    let seconds_per_column = spectrogram
+        .seconds_per_column()
+        .context("spectrogram has no sample rate or hop")?;
+    if seconds_per_column > config.fine_seconds_per_column {
+        log::warn!(
+            "Spectrogram has {seconds_per_column:.3} s per column, crops at {:.3} s are upsampled",
+            config.fine_seconds_per_column
+        );
+    }
+
+    let coarse = spectrogram.to_time_grid(&config.coarse)?;
+    let crops = boundaries
+        .iter()
+        .map(|&(boundary, time)| {
+            crop_around(spectrogram, seconds_per_column, boundary, time, config)
+        })
+        .collect::<Result<_>>()?;
+    Ok(SpectrogramPyramid { coarse, crops })
+}
+
+/// [`build_pyramid`] around the labeled boundaries.
+pub fn label_pyramid(
+    spectrogram: &ZaoSpectrogram,
+    label: &ZaoaiLabel,
+    config: &PyramidConfig,
+) -> Result<SpectrogramPyramid> {
+    build_pyramid(spectrogram, &label_boundaries(label), config)
+}
+
+fn crop_around(
+    spectrogram: &ZaoSpectrogram,
+    seconds_per_column: f64,
+    boundary: SectionBoundary,
+    time: Duration,
+    config: &PyramidConfig,
+) -> Result<PyramidCrop> {
+    let total = spectrogram.width() as f64 * seconds_per_column;
+    let span = 2.0 * config.context.as_secs_f64();
+    let start = (time.as_secs_f64() - config.context.as_secs_f64())
+        .min(total - span)
+        .max(0.0);
+
+    let first = (start / seconds_per_column).floor() as usize;
+    let last = (((start + span) / seconds_per_column).ceil() as usize).min(spectrogram.width());
+    let columns = (span / config.fine_seconds_per_column).round() as usize;
+    let crop = spectrogram
+        .slice_columns(first..last)
+        .with_seconds_per_column(config.fine_seconds_per_column)?
+        .fit_width(columns, None);
+
+    Ok(PyramidCrop {
+        boundary,
+        time,
+        offset: Duration::from_secs_f64(first as f64 * seconds_per_column),
+        spectrogram: crop,
+    })
+}
+
+pub fn save_pyramid(pyramid: &SpectrogramPyramid, path: impl AsRef<Path>) -> Result<()> {
+    CONTAINER.save(pyramid, path)
+}
+
+pub fn load_pyramid(path: impl AsRef<Path>) -> Result<SpectrogramPyramid> {
+    CONTAINER.load(path)
+}
@@ -0,0 +1,305 @@
+//! Rule-based music / speech / silence segmentation of an episode.
+//!
+//! Every window (one second by default) is classified from frame features
+//! alone: speech alternates between syllables and short pauses, so it has
+//! many low-energy frames and a strongly varying zero-crossing rate, while
+//! music keeps a steady energy floor.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::features::{FeatureConfig, Features, compute_features, mean, rms_to_db};
+use crate::sound::{DecodeOptions, decode_audio, frames_to_duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SegmentClass {
+    Silence,
+    Speech,
+    Music,
+    /// Music under dialogue, or too ambiguous to call.
+    Mixed,
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmenterConfig {
+    pub window: Duration,
+    pub features: FeatureConfig,
+    /// Frames below this RMS level (dBFS) are silent, windows that are mostly silent frames are silence.
+    pub silence_db: f32,
+    /// Low-energy frame ratio typical of music and of speech.
+    pub low_energy_ratio: (f32, f32),
+    /// Zero-crossing rate coefficient of variation typical of music and of speech.
+    pub zcr_variation: (f32, f32),
+    /// Windows with a speechiness up to this value are music.
+    pub music_threshold: f32,
+    /// Windows with a speechiness from this value on are speech.
+    pub speech_threshold: f32,
+    /// Odd number of windows in the majority filter run over the classes, 1 disables it.
+    pub smoothing: usize,
+}
+
+impl Default for SegmenterConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(1),
+            // Short frames so that pauses between syllables show up as low-energy frames.
+            features: FeatureConfig {
+                frame_len: 1024,
+                hop: 512,
+                ..FeatureConfig::default()
+            },
+            silence_db: -50.0,
+            low_energy_ratio: (0.1, 0.4),
+            zcr_variation: (0.3, 0.9),
+            music_threshold: 0.35,
+            speech_threshold: 0.65,
+            smoothing: 3,
+        }
+    }
+}
+
+/// Statistics of one classification window, usable as model features.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowStats {
+    pub start: Duration,
+    pub rms_db: f32,
+    /// Fraction of frames with less than half the window's mean RMS.
+    pub low_energy_ratio: f32,
+    /// Standard deviation over mean of the zero-crossing rate.
+    pub zcr_variation: f32,
+    /// 0 for clearly music, 1 for clearly speech.
+    pub speechiness: f32,
+    pub class: SegmentClass,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    pub class: SegmentClass,
+    pub start: Duration,
+    pub end: Duration,
+}
+
+impl Segment {
+    pub fn duration(&self) -> Duration {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+/// Classifies every `config.window` of `features`, before smoothing.
+pub fn classify_windows(features: &Features, config: &SegmenterConfig) -> Vec<WindowStats> {
+    let window = config.window.as_secs_f64();
+    if features.n_frames() == 0 || window <= 0.0 {
+        return Vec::new();
+    }
+
+    // Grouped by frame start, which unlike the centre always lies inside the audio.
+    let hop = features.hop_duration().as_secs_f64();
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for frame in 0..features.n_frames() {
+        let index = (frame as f64 * hop / window) as usize;
+        if groups.len() <= index {
+            groups.resize_with(index + 1, Vec::new);
+        }
+        groups[index].push(frame);
+    }
+
+    groups
+        .iter()
+        .enumerate()
+        .map(|(index, frames)| {
+            let start = features.offset + config.window * index as u32;
+            window_stats(features, frames, start, config)
+        })
+        .collect()
+}
+
+fn window_stats(
+    features: &Features,
+    frames: &[usize],
+    start: Duration,
+    config: &SegmenterConfig,
+) -> WindowStats {
+    let rms: Vec<f32> = frames.iter().map(|&i| features.rms[i]).collect();
+    let zcr: Vec<f32> = frames.iter().map(|&i| features.zcr[i]).collect();
+
+    let mean_rms = mean(&rms);
+    let rms_db = rms_to_db(mean_rms);
+    let silent_frames = rms
+        .iter()
+        .filter(|&&r| rms_to_db(r) < config.silence_db)
+        .count();
+    let low_energy_ratio = if rms.is_empty() {
+        0.0
+    } else {
+        rms.iter().filter(|&&r| r < 0.5 * mean_rms).count() as f32 / rms.len() as f32
+    };
+
+    let mean_zcr = mean(&zcr);
+    let zcr_variation = if mean_zcr > 0.0 {
+        let var = zcr.iter().map(|z| (z - mean_zcr).powi(2)).sum::<f32>() / zcr.len() as f32;
+        var.sqrt() / mean_zcr
+    } else {
+        0.0
+    };
+
+    let ramp = |value: f32, (music, speech): (f32, f32)| {
+        ((value - music) / (speech - music)).clamp(0.0, 1.0)
+    };
+    let speechiness = 0.5 * ramp(low_energy_ratio, config.low_energy_ratio)
+        + 0.5 * ramp(zcr_variation, config.zcr_variation);
+
+    let class = if 2 * silent_frames >= rms.len() {
+        SegmentClass::Silence
+    } else if speechiness <= config.music_threshold {
+        SegmentClass::Music
+    } else if speechiness >= config.speech_threshold {
+        SegmentClass::Speech
+    } else {
+        SegmentClass::Mixed
+    };
+
+    WindowStats {
+        start,
+        rms_db,
+        low_energy_ratio,
+        zcr_variation,
+        speechiness,
+        class,
+    }
+}
+
+/// Replaces every class by the most common one among its `width` neighbours,
+/// keeping the original on ties.
+pub fn smooth_classes(classes: &[SegmentClass], width: usize) -> Vec<SegmentClass> {
+    let half = width / 2;
+    if half == 0 {
+        return classes.to_vec();
+    }
+
+    (0..classes.len())
+        .map(|i| {
+            let neighbours = &classes[i.saturating_sub(half)..(i + half + 1).min(classes.len())];
+            let count = |class: SegmentClass| neighbours.iter().filter(|&&c| c == class).count();
+            let own = count(classes[i]);
+            [
+                SegmentClass::Silence,
+                SegmentClass::Speech,
+                SegmentClass::Music,
+                SegmentClass::Mixed,
+            ]
+            .into_iter()
+            .map(|class| (count(class), class))
+            .filter(|&(n, _)| n > own)
+            .max_by_key(|&(n, _)| n)
+            .map_or(classes[i], |(_, class)| class)
+        })
+        .collect()
+}
+
+/// Merges runs of equal classes into segments, the last one ending at `end`.
+pub fn segments_from_windows(
+    windows: &[WindowStats],
+    classes: &[SegmentClass],
+    end: Duration,
+) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    for (i, (window, &class)) in windows.iter().zip(classes).enumerate() {
+        let window_end = windows.get(i + 1).map_or(end, |next| next.start);
+        match segments.last_mut() {
+            Some(last) if last.class == class => last.end = window_end,
+            _ => segments.push(Segment {
+                class,
+                start: window.start,
+                end: window_end,
+            }),
+        }
+    }
+    segments
+}
+
+/// Segment timeline of already computed features.
+pub fn segment_features(features: &Features, config: &SegmenterConfig) -> Vec<Segment> {
+    let windows = classify_windows(features, config);
+    let classes: Vec<SegmentClass> = windows.iter().map(|w| w.class).collect();
+    let classes = smooth_classes(&classes, config.smoothing);
+    // Frames start every hop while inside the audio, so this is the audio's end to within a hop.
+    let end = features.offset + features.hop_duration() * features.n_frames() as u32;
+    segments_from_windows(&windows, &classes, end)
+}
+
+/// Segment timeline of in-memory mono `samples`, `offset` being the track time of `samples[0]`.
+pub fn segment_samples(
+    samples: &[f32],
+    sample_rate: u32,
+    offset: Duration,
+    config: &SegmenterConfig,
+) -> Result<Vec<Segment>> {
+    let features = compute_features(samples, sample_rate, offset, &config.features)?;
+    let mut segments = segment_features(&features, config);
+    if let Some(last) = segments.last_mut() {
+        last.end = offset + frames_to_duration(samples.len() as u64, sample_rate);
+    }
+    Ok(segments)
+}
+
+/// Decodes `path` (through the audio cache, if installed) and segments it.
+pub fn segment_audio(
+    path: impl AsRef<Path>,
+    decode_options: &DecodeOptions,
+    config: &SegmenterConfig,
+) -> Result<Vec<Segment>> {
+    let path = path.as_ref();
+    let audio = decode_audio(path, decode_options)
+        .with_context(|| format!("Failed to decode: {}", path.display()))?;
+    segment_samples(&audio.samples, audio.sample_rate, audio.offset, config)
+}
+
+/// Fraction of `[start, end)` covered by segments of `class`, e.g. to check
+/// that a chapter-derived opening is mostly music.
+pub fn class_fraction(
+    segments: &[Segment],
+    class: SegmentClass,
+    start: Duration,
+    end: Duration,
+) -> f32 {
+    let span = end.saturating_sub(start);
+    if span.is_zero() {
+        return 0.0;
+    }
+    let covered: Duration = segments
+        .iter()
+        .filter(|s| s.class == class)
+        .map(|s| s.end.min(end).saturating_sub(s.start.max(start)))
+        .sum();
+    (covered.as_secs_f64() / span.as_secs_f64()) as f32
+}
+
+/// Baseline opening detector: the longest run of music, bridging
+/// interruptions shorter than `max_gap`, that lasts at least `min_len`.
+pub fn longest_music_run(
+    segments: &[Segment],
+    max_gap: Duration,
+    min_len: Duration,
+) -> Option<Segment> {
+    let mut best: Option<Segment> = None;
+    let mut current: Option<Segment> = None;
+
+    for segment in segments.iter().filter(|s| s.class == SegmentClass::Music) {
+        current = match current {
+            Some(mut run) if segment.start.saturating_sub(run.end) <= max_gap => {
+                run.end = segment.end;
+                Some(run)
+            }
+            _ => Some(*segment),
+        };
+        let run = current.unwrap();
+        if best.is_none_or(|b| run.duration() > b.duration()) {
+            best = Some(run);
+        }
+    }
+
+    best.filter(|run| run.duration() >= min_len)
+}
@@ -1,20 +1,31 @@
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::Duration;
 
 use anyhow::Context;
 use anyhow::Result;
 //use symphonia::core::sample;
-use symphonia::core::audio::{AudioBufferRef, Signal};
-use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::audio::{AudioBufferRef, SampleBuffer};
+use symphonia::core::codecs::{Decoder, DecoderOptions};
 use symphonia::core::errors::Error;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
 use symphonia::core::meta::{MetadataOptions, MetadataRevision};
 use symphonia::core::probe::Hint;
-use symphonia::core::units::TimeBase;
+use symphonia::core::units::{Time, TimeBase};
 
+pub mod loudness;
+pub mod resample;
+
+use crate::cache;
+use crate::ebml;
+use crate::mkv::{MkvTrackTiming, read_track_timings};
+use crate::progress::{Amount, JobControl, LogProgress, Progress, Stage};
+use crate::sound::loudness::{LoudnessMeter, LoudnessStats, apply_gain};
+use serde::{Deserialize, Serialize};
 use symphonia::core::codecs::{
     CODEC_TYPE_AAC, CODEC_TYPE_FLAC, CODEC_TYPE_MP3, CODEC_TYPE_NULL, CODEC_TYPE_OPUS,
     CODEC_TYPE_PCM_F32LE, CODEC_TYPE_PCM_S16LE, CodecType,
@@ -30,6 +41,1024 @@ const SUPPORTED_AUDIO_CODECS: &[CodecType] = &[
     // Add more as needed...
 ];
 
+/// Number of mono frames per chunk handed out by [`AudioStream`] by default.
+pub const DEFAULT_CHUNK_FRAMES: usize = 4096;
+
+/// Consecutive corrupt packets tolerated before a stream is considered unreadable.
+const MAX_CONSECUTIVE_ERRORS: u32 = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("failed to open {}: {source}", path.display())]
+    Open {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("unrecognized container format: {0}")]
+    Probe(#[source] Error),
+    #[error("no decodable audio track found")]
+    NoAudioTrack,
+    #[error("audio track does not report a sample rate")]
+    UnknownSampleRate,
+    #[error("invalid decode options: {0}")]
+    InvalidOptions(&'static str),
+    #[error("io error while decoding: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unrecoverable error while decoding: {0}")]
+    Fatal(#[source] Error),
+    #[error("gave up after {0} consecutive corrupt packets")]
+    TooManyErrors(u32),
+    #[error("decoding cancelled")]
+    Cancelled,
+}
+
+/// A stretch of the track that could not be decoded.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DecodeGap {
+    /// Track time where the gap starts.
+    #[serde(with = "humantime_serde")]
+    pub at: Duration,
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    pub reason: String,
+}
+
+/// What happened while decoding a single file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DecodeReport {
+    /// Empty for streams opened with [`AudioStream::from_source`].
+    pub path: PathBuf,
+    pub packets_decoded: u64,
+    pub packets_skipped: u64,
+    pub demux_errors: u64,
+    /// Number of frames handed out, including silence filled in for gaps.
+    pub frames: u64,
+    pub gaps: Vec<DecodeGap>,
+    /// The container asked for a decoder reset (chained streams), decoding stopped there.
+    pub reset_required: bool,
+    #[serde(default)]
+    pub timing: TrackTiming,
+    /// Filled in when [`DecodeOptions::measure_loudness`] is set.
+    #[serde(default)]
+    pub loudness: Option<LoudnessStats>,
+}
+
+/// How the decoded samples were placed on the container timeline.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TrackTiming {
+    /// Timestamp of the first audio packet, non-zero when the audio track starts late.
+    #[serde(with = "humantime_serde")]
+    pub first_packet: Duration,
+    /// Codec delay (priming) subtracted from packet timestamps and dropped from the output.
+    #[serde(with = "humantime_serde")]
+    pub codec_delay: Duration,
+    /// Extra audio decoded and discarded before a seek target.
+    #[serde(with = "humantime_serde")]
+    pub seek_pre_roll: Duration,
+    /// Encoder delay and padding frames trimmed from gapless metadata (LAME/iTunes tags).
+    pub gapless_delay_frames: u32,
+    pub gapless_padding_frames: u32,
+    /// Silence prepended so that sample 0 sits at the requested start time.
+    #[serde(with = "humantime_serde")]
+    pub leading_silence: Duration,
+}
+
+impl DecodeReport {
+    pub fn is_clean(&self) -> bool {
+        self.gaps.is_empty() && self.demux_errors == 0 && !self.reset_required
+    }
+
+    pub fn total_gap_duration(&self) -> Duration {
+        self.gaps.iter().map(|gap| gap.duration).sum()
+    }
+}
+
+/// A `[start, end)` window of a track, `end: None` meaning until the end of the track.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct TimeRange {
+    #[serde(with = "humantime_serde")]
+    pub start: Duration,
+    #[serde(with = "humantime_serde")]
+    pub end: Option<Duration>,
+}
+
+impl TimeRange {
+    pub fn new(start: Duration, end: Duration) -> Self {
+        Self {
+            start,
+            end: Some(end),
+        }
+    }
+
+    pub fn from_start(start: Duration) -> Self {
+        Self { start, end: None }
+    }
+
+    /// Length of the range, `None` if it is open ended.
+    pub fn duration(&self) -> Option<Duration> {
+        self.end.map(|end| end.saturating_sub(self.start))
+    }
+
+    pub fn contains(&self, time: Duration) -> bool {
+        time >= self.start && self.end.is_none_or(|end| time < end)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecodeOptions {
+    pub chunk_frames: usize,
+    /// Only decode this part of the track. Seeks in the container and trims
+    /// the decoded packets so the first sample lands exactly on `range.start`.
+    pub range: Option<TimeRange>,
+    /// Replace undecodable packets with silence of the same duration, keeping
+    /// later samples aligned with the track timeline.
+    pub fill_gaps: bool,
+    /// Log container metadata tags as they are read.
+    pub log_metadata: bool,
+    /// Pad with silence when the audio track starts after the requested
+    /// start time, so `samples[0]` is always at `range.start` (or zero).
+    /// Otherwise the late start is only reported through the offset.
+    pub align_to_timeline: bool,
+    /// Priming frames to drop for encoders the container does not describe,
+    /// e.g. 1024 or 2112 for AAC muxed into Matroska without `CodecDelay`.
+    pub priming_frames: Option<u32>,
+    /// Run an EBU R128 meter over the decoded audio, results land in [`DecodeReport::loudness`].
+    pub measure_loudness: bool,
+    /// Scale the output of [`decode_audio`] to this integrated loudness.
+    /// Implies `measure_loudness`. Streams cannot be normalized as the
+    /// integrated loudness is only known at the end.
+    pub normalize_to_lufs: Option<f64>,
+    /// Resample the output of [`decode_audio`] to this rate. Streams always
+    /// yield the native rate of the track.
+    pub target_sample_rate: Option<u32>,
+    /// Receives decoded time per file and stops the stream once cancelled.
+    pub control: JobControl,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            chunk_frames: DEFAULT_CHUNK_FRAMES,
+            range: None,
+            fill_gaps: true,
+            log_metadata: false,
+            align_to_timeline: true,
+            priming_frames: None,
+            measure_loudness: false,
+            normalize_to_lufs: None,
+            target_sample_rate: None,
+            control: JobControl::default(),
+        }
+    }
+}
+
+impl DecodeOptions {
+    pub fn with_range(mut self, range: TimeRange) -> Self {
+        self.range = Some(range);
+        self
+    }
+}
+
+/// A fully decoded mono track (or part of one).
+#[derive(Debug, Clone, Default)]
+pub struct DecodedAudio {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    /// Track time of `samples[0]`.
+    pub offset: Duration,
+    pub report: DecodeReport,
+}
+
+impl DecodedAudio {
+    pub fn duration(&self) -> Duration {
+        frames_to_duration(self.samples.len() as u64, self.sample_rate)
+    }
+
+    /// Track time of the frame at `index`.
+    pub fn time_of_frame(&self, index: u64) -> Duration {
+        self.offset + frames_to_duration(index, self.sample_rate)
+    }
+
+    /// Frame index of the track time `time`, `None` if it lies before `offset`.
+    pub fn frame_at(&self, time: Duration) -> Option<u64> {
+        let rel = time.checked_sub(self.offset)?;
+        Some(duration_to_frames(rel, self.sample_rate))
+    }
+}
+
+pub fn frames_to_duration(frames: u64, sample_rate: u32) -> Duration {
+    if sample_rate == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_secs_f64(frames as f64 / sample_rate as f64)
+}
+
+pub fn duration_to_frames(duration: Duration, sample_rate: u32) -> u64 {
+    (duration.as_secs_f64() * sample_rate as f64).round() as u64
+}
+
+/// Decodes the whole track, or `options.range` of it, into memory.
+///
+/// Served from the [`cache::global_cache`] when one is installed.
+pub fn decode_audio(
+    path: impl AsRef<Path>,
+    options: &DecodeOptions,
+) -> Result<DecodedAudio, DecodeError> {
+    let path = path.as_ref();
+    match cache::global_cache() {
+        Some(cache) => cache.get_or_insert_with(path, &cache_settings(options), || {
+            decode_audio_uncached(path, options)
+        }),
+        None => decode_audio_uncached(path, options),
+    }
+}
+
+/// [`decode_audio`] bypassing the cache.
+pub fn decode_audio_uncached(
+    path: impl AsRef<Path>,
+    options: &DecodeOptions,
+) -> Result<DecodedAudio, DecodeError> {
+    read_to_end(AudioStream::open_with(path, options)?, options)
+}
+
+/// The options that change the decoded samples, see [`cache::AudioCache::key`].
+fn cache_settings(options: &DecodeOptions) -> serde_json::Value {
+    serde_json::json!({
+        "decoder": "symphonia",
+        "range": options.range,
+        "fill_gaps": options.fill_gaps,
+        "align_to_timeline": options.align_to_timeline,
+        "priming_frames": options.priming_frames,
+        "measure_loudness": options.measure_loudness,
+        "normalize_to_lufs": options.normalize_to_lufs,
+        "target_sample_rate": options.target_sample_rate,
+    })
+}
+
+/// Like [`decode_audio`] but reads from an in-memory buffer, archive entry or
+/// any other [`MediaSource`], e.g. `Box::new(Cursor::new(bytes))`.
+pub fn decode_audio_from_source(
+    source: Box<dyn MediaSource>,
+    hint: &SourceHint,
+    options: &DecodeOptions,
+) -> Result<DecodedAudio, DecodeError> {
+    read_to_end(AudioStream::from_source(source, hint, options)?, options)
+}
+
+fn read_to_end(
+    mut stream: AudioStream,
+    options: &DecodeOptions,
+) -> Result<DecodedAudio, DecodeError> {
+    let mut samples = Vec::new();
+    if let Some(n_frames) = stream.expected_frames() {
+        samples.reserve(n_frames as usize);
+    }
+    for chunk in stream.by_ref() {
+        samples.extend_from_slice(&chunk?.samples);
+    }
+
+    let report = stream.report().clone();
+    if let (Some(target), Some(stats)) = (options.normalize_to_lufs, report.loudness) {
+        apply_gain(&mut samples, stats.normalization_gain(target, None));
+    }
+
+    let mut sample_rate = stream.sample_rate();
+    if let Some(target) = options.target_sample_rate {
+        samples = resample::resample(&samples, sample_rate, target);
+        sample_rate = target;
+    }
+
+    Ok(DecodedAudio {
+        samples,
+        sample_rate,
+        offset: stream.start_time(),
+        report,
+    })
+}
+
+/// What a [`MediaSource`] without a file name contains, used to pick the demuxer.
+#[derive(Debug, Clone, Default)]
+pub struct SourceHint {
+    /// File extension without the dot, e.g. `"mkv"`.
+    pub extension: Option<String>,
+    /// e.g. `"video/x-matroska"`.
+    pub mime_type: Option<String>,
+}
+
+impl SourceHint {
+    pub fn extension(extension: impl Into<String>) -> Self {
+        Self {
+            extension: Some(extension.into()),
+            mime_type: None,
+        }
+    }
+
+    pub fn mime_type(mime_type: impl Into<String>) -> Self {
+        Self {
+            extension: None,
+            mime_type: Some(mime_type.into()),
+        }
+    }
+
+    fn to_hint(&self) -> Hint {
+        let mut hint = Hint::new();
+        if let Some(extension) = &self.extension {
+            hint.with_extension(extension);
+        }
+        if let Some(mime_type) = &self.mime_type {
+            hint.mime_type(mime_type);
+        }
+        hint
+    }
+}
+
+/// A fixed-size block of decoded mono samples.
+#[derive(Debug, Clone)]
+pub struct AudioChunk {
+    /// Index of the first frame in this chunk, counted from the start of the stream.
+    pub start_frame: u64,
+    pub samples: Vec<f32>,
+}
+
+/// Incremental decoder over the first decodable audio track of a file.
+///
+/// Yields mono (channel averaged) chunks of `chunk_frames` samples, only the
+/// last chunk may be shorter. At most one chunk plus one packet is kept in
+/// memory, so a full episode never has to be materialized at once.
+///
+/// Corrupt packets are skipped and recorded in [`AudioStream::report`]
+/// instead of aborting, only unrecoverable errors end the stream with an `Err`.
+pub struct AudioStream {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    sample_rate: u32,
+    time_base: Option<TimeBase>,
+    n_frames: Option<u64>,
+    chunk_frames: usize,
+    range: Option<TimeRange>,
+    fill_gaps: bool,
+    log_metadata: bool,
+    align_to_timeline: bool,
+    /// The decoder applies packet trimming itself, see [`FormatOptions::enable_gapless`].
+    decoder_trims: bool,
+    /// Track time of the first emitted frame, settled by the first decoded packet.
+    start_time: Duration,
+    /// Frames still to drop before `range.start`, `None` until the first packet is seen.
+    skip_frames: Option<u64>,
+    /// Maximum number of frames to emit, derived from `range.end`.
+    frame_limit: Option<u64>,
+    /// Timestamp the next packet should have, used to measure gaps after demux errors.
+    next_ts: Option<u64>,
+    resync_pending: bool,
+    consecutive_errors: u32,
+    loudness_meter: Option<LoudnessMeter>,
+    control: JobControl,
+    sample_buf: Option<SampleBuffer<f32>>,
+    pending: VecDeque<f32>,
+    emitted_frames: u64,
+    finished: bool,
+    report: DecodeReport,
+}
+
+impl AudioStream {
+    pub fn open(path: impl AsRef<Path>, chunk_frames: usize) -> Result<Self, DecodeError> {
+        Self::open_with(
+            path,
+            &DecodeOptions {
+                chunk_frames,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn open_with(path: impl AsRef<Path>, options: &DecodeOptions) -> Result<Self, DecodeError> {
+        let path = path.as_ref();
+        let src = File::open(path).map_err(|source| DecodeError::Open {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let hint = SourceHint::extension(hint_extension(path));
+        Self::open_source(Box::new(src), &hint, path.to_path_buf(), options)
+    }
+
+    /// Opens any seekable source, `hint` stands in for the missing file name.
+    pub fn from_source(
+        source: Box<dyn MediaSource>,
+        hint: &SourceHint,
+        options: &DecodeOptions,
+    ) -> Result<Self, DecodeError> {
+        Self::open_source(source, hint, PathBuf::new(), options)
+    }
+
+    fn open_source(
+        mut source: Box<dyn MediaSource>,
+        hint: &SourceHint,
+        path: PathBuf,
+        options: &DecodeOptions,
+    ) -> Result<Self, DecodeError> {
+        if options.chunk_frames == 0 {
+            return Err(DecodeError::InvalidOptions("chunk_frames must be non-zero"));
+        }
+        if options.target_sample_rate == Some(0) {
+            return Err(DecodeError::InvalidOptions(
+                "target_sample_rate must be non-zero",
+            ));
+        }
+
+        // symphonia does not expose the Matroska codec delay, read it up front
+        // before the source is handed over.
+        let mkv_timings = if source.is_seekable() && is_matroska(&mut source)? {
+            let timings = read_track_timings(&mut BufReader::new(&mut source));
+            source.seek(SeekFrom::Start(0))?;
+            Some(timings)
+        } else {
+            None
+        };
+
+        let mss = MediaSourceStream::new(source, Default::default());
+        let hint = hint.to_hint();
+
+        let fmt_opts = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe()
+            .format(&hint, mss, &fmt_opts, &MetadataOptions::default())
+            .map_err(DecodeError::Probe)?;
+        let mut format = probed.format;
+
+        // Pick the first supported audio track a decoder can actually be made for.
+        let (track, mut decoder) = format
+            .tracks()
+            .iter()
+            .filter(|t| {
+                let codec = t.codec_params.codec;
+                codec != CODEC_TYPE_NULL && SUPPORTED_AUDIO_CODECS.contains(&codec)
+            })
+            .find_map(|t| {
+                let decoder = symphonia::default::get_codecs()
+                    .make(&t.codec_params, &DecoderOptions::default())
+                    .ok()?;
+                Some((t.clone(), decoder))
+            })
+            .ok_or(DecodeError::NoAudioTrack)?;
+
+        let track_id = track.id;
+        let sample_rate = track
+            .codec_params
+            .sample_rate
+            .ok_or(DecodeError::UnknownSampleRate)?;
+
+        let mut timing = TrackTiming {
+            gapless_delay_frames: track.codec_params.delay.unwrap_or_default(),
+            gapless_padding_frames: track.codec_params.padding.unwrap_or_default(),
+            ..Default::default()
+        };
+        match mkv_timings.map(|t| find_track_timing(t, track_id)) {
+            Some(Ok(Some(mkv))) => {
+                timing.codec_delay = mkv.codec_delay;
+                timing.seek_pre_roll = mkv.seek_pre_roll;
+            }
+            Some(Err(e)) => log::warn!("Could not read track timing of {}: {e}", path.display()),
+            _ => {}
+        }
+        if let Some(priming) = options.priming_frames {
+            timing.codec_delay += frames_to_duration(priming as u64, sample_rate);
+        }
+
+        let range = options.range;
+        if let Some(range) = range.filter(|r| !r.start.is_zero()) {
+            // Accurate seeks land on or before the requested time, the rest is
+            // trimmed sample-accurately once the first packet is decoded.
+            let target = range.start.saturating_sub(timing.seek_pre_roll);
+            let seek_to = SeekTo::Time {
+                time: Time::from(target.as_secs_f64()),
+                track_id: Some(track_id),
+            };
+            match format.seek(SeekMode::Accurate, seek_to) {
+                Ok(_) => decoder.reset(),
+                Err(e) => log::warn!(
+                    "Seek failed in {}, decoding from the start instead: {e}",
+                    path.display()
+                ),
+            }
+        }
+
+        Ok(Self {
+            format,
+            decoder,
+            track_id,
+            sample_rate,
+            time_base: track.codec_params.time_base,
+            n_frames: track.codec_params.n_frames,
+            chunk_frames: options.chunk_frames,
+            range,
+            fill_gaps: options.fill_gaps,
+            log_metadata: options.log_metadata,
+            align_to_timeline: options.align_to_timeline,
+            decoder_trims: track.codec_params.codec == CODEC_TYPE_MP3,
+            start_time: range.map(|r| r.start).unwrap_or_default(),
+            skip_frames: None,
+            frame_limit: None,
+            next_ts: None,
+            resync_pending: false,
+            consecutive_errors: 0,
+            loudness_meter: (options.measure_loudness || options.normalize_to_lufs.is_some())
+                .then(|| LoudnessMeter::new(sample_rate)),
+            control: options.control.clone(),
+            sample_buf: None,
+            pending: VecDeque::with_capacity(options.chunk_frames * 2),
+            emitted_frames: 0,
+            finished: false,
+            report: DecodeReport {
+                path,
+                timing,
+                ..Default::default()
+            },
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Length of the track, if the container reports it.
+    pub fn track_duration(&self) -> Option<Duration> {
+        // Containers report this in time base ticks, which are only frames
+        // when the time base is 1 / sample_rate (Matroska uses milliseconds).
+        self.n_frames.map(|n| self.ts_to_duration(n))
+    }
+
+    pub fn timing(&self) -> &TrackTiming {
+        &self.report.timing
+    }
+
+    pub fn chunk_frames(&self) -> usize {
+        self.chunk_frames
+    }
+
+    /// Track time of the first emitted frame.
+    ///
+    /// Equals `range.start` when decoding a range, unless the track begins later than that.
+    pub fn start_time(&self) -> Duration {
+        self.start_time
+    }
+
+    /// Gaps and error counts so far, complete once the stream is exhausted.
+    pub fn report(&self) -> &DecodeReport {
+        &self.report
+    }
+
+    pub fn into_report(self) -> DecodeReport {
+        self.report
+    }
+
+    /// Number of frames the stream is expected to yield, if known up front.
+    pub fn expected_frames(&self) -> Option<u64> {
+        let range = self.range.unwrap_or_default();
+        let total = self.track_duration();
+        let end = match (range.end, total) {
+            (Some(end), Some(total)) => end.min(total),
+            (Some(end), None) => end,
+            (None, Some(total)) => total,
+            (None, None) => return None,
+        };
+        Some(duration_to_frames(
+            end.saturating_sub(range.start),
+            self.sample_rate,
+        ))
+    }
+
+    /// Wraps the stream so it yields overlapping frames of `frame_len` samples advancing by `hop`.
+    pub fn framed(self, frame_len: usize, hop: usize) -> FramedAudioStream {
+        FramedAudioStream::new(self, frame_len, hop)
+    }
+
+    /// Decodes packets until at least one chunk is buffered or the stream ends.
+    fn fill_pending(&mut self) -> Result<(), DecodeError> {
+        while !self.finished && self.pending.len() < self.chunk_frames {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    self.finished = true;
+                    break;
+                }
+                Err(Error::ResetRequired) => {
+                    // Only used for chained OGG streams, treat it as the end of the track.
+                    self.report.reset_required = true;
+                    self.finished = true;
+                    break;
+                }
+                Err(Error::DecodeError(e)) => {
+                    // Malformed container data, the reader resyncs on the next packet.
+                    log::warn!("Demux error in {}: {e}", self.report.path.display());
+                    self.report.demux_errors += 1;
+                    self.resync_pending = true;
+                    self.count_error()?;
+                    continue;
+                }
+                Err(Error::IoError(e)) => return Err(DecodeError::Io(e)),
+                Err(e) => return Err(DecodeError::Fatal(e)),
+            };
+
+            if self.log_metadata {
+                log_metadata_revisions(self.format.as_mut());
+            }
+
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            if self.skip_frames.is_none() {
+                self.settle_timeline(packet.ts);
+            }
+
+            if std::mem::take(&mut self.resync_pending)
+                && let Some(expected) = self.next_ts.filter(|&ts| packet.ts > ts)
+            {
+                let missing = packet.ts - expected;
+                if missing * 2 > packet.dur {
+                    self.record_gap(expected, missing, "packets lost to demux error");
+                }
+            }
+            self.next_ts = Some(packet.ts + packet.dur);
+
+            let (trim_start, trim_end) = if self.decoder_trims {
+                (0, 0)
+            } else {
+                (packet.trim_start as usize, packet.trim_end as usize)
+            };
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(Error::DecodeError(e)) => {
+                    self.report.packets_skipped += 1;
+                    self.record_gap(packet.ts, packet.dur, e);
+                    self.count_error()?;
+                    continue;
+                }
+                Err(Error::ResetRequired) => {
+                    self.decoder.reset();
+                    self.report.packets_skipped += 1;
+                    self.record_gap(packet.ts, packet.dur, "decoder reset");
+                    continue;
+                }
+                Err(Error::IoError(e)) => return Err(DecodeError::Io(e)),
+                Err(e) => return Err(DecodeError::Fatal(e)),
+            };
+
+            self.consecutive_errors = 0;
+            self.report.packets_decoded += 1;
+
+            let before = self.pending.len();
+            push_downmixed(&mut self.sample_buf, &mut self.pending, decoded);
+            if trim_start + trim_end > 0 {
+                // Gapless trimming for decoders that leave it to the caller.
+                let added = self.pending.len() - before;
+                self.pending
+                    .truncate(before + added.saturating_sub(trim_end));
+                let front = trim_start.min(self.pending.len() - before);
+                self.pending.drain(before..before + front);
+            }
+            self.trim_queued(before);
+        }
+
+        Ok(())
+    }
+
+    fn count_error(&mut self) -> Result<(), DecodeError> {
+        self.consecutive_errors += 1;
+        if self.consecutive_errors > MAX_CONSECUTIVE_ERRORS {
+            return Err(DecodeError::TooManyErrors(self.consecutive_errors));
+        }
+        Ok(())
+    }
+
+    /// Records `dur` ticks starting at `ts` as a gap and, if enabled, queues silence for it.
+    fn record_gap(&mut self, ts: u64, dur: u64, reason: impl ToString) {
+        let gap = DecodeGap {
+            at: self.ts_to_duration(ts),
+            duration: self.ts_to_duration(dur),
+            reason: reason.to_string(),
+        };
+        log::warn!(
+            "Decode gap in {} at {:?} ({:?}): {}",
+            self.report.path.display(),
+            gap.at,
+            gap.duration,
+            gap.reason
+        );
+
+        if self.fill_gaps {
+            let before = self.pending.len();
+            let frames = duration_to_frames(gap.duration, self.sample_rate) as usize;
+            self.pending.extend(std::iter::repeat_n(0.0, frames));
+            self.trim_queued(before);
+        }
+        self.report.gaps.push(gap);
+    }
+
+    /// Applies the range trimming to the samples queued after index `before`.
+    fn trim_queued(&mut self, before: usize) {
+        if let Some(skip) = self.skip_frames.as_mut().filter(|skip| **skip > 0) {
+            let added = (self.pending.len() - before) as u64;
+            let dropped = added.min(*skip);
+            self.pending.drain(before..before + dropped as usize);
+            *skip -= dropped;
+        }
+
+        if let Some(limit) = self.frame_limit {
+            let queued = self.emitted_frames + self.pending.len() as u64;
+            if queued >= limit {
+                self.pending
+                    .truncate(limit.saturating_sub(self.emitted_frames) as usize);
+                self.finished = true;
+            }
+        }
+    }
+
+    fn ts_to_duration(&self, ts: u64) -> Duration {
+        match self.time_base {
+            Some(tb) => {
+                let t = tb.calc_time(ts);
+                Duration::from_secs(t.seconds) + Duration::from_secs_f64(t.frac)
+            }
+            None => frames_to_duration(ts, self.sample_rate),
+        }
+    }
+
+    /// Places the stream on the container timeline from the timestamp of the
+    /// first decoded packet, i.e. where the seek actually landed.
+    ///
+    /// Works out how much to drop before the target time (codec delay plus
+    /// seek overshoot), how much silence to prepend when the track starts
+    /// late, and the frame limit for `range.end`.
+    fn settle_timeline(&mut self, first_ts: u64) {
+        let target = self.range.map(|r| r.start).unwrap_or_default();
+        let packet_time = if self.time_base.is_some() {
+            self.ts_to_duration(first_ts)
+        } else {
+            Duration::ZERO
+        };
+        self.report.timing.first_packet = packet_time;
+
+        // Presentation time of the first decoded sample, which may be negative.
+        let decoded_start =
+            packet_time.as_secs_f64() - self.report.timing.codec_delay.as_secs_f64();
+        let target_secs = target.as_secs_f64();
+        let sr = self.sample_rate as f64;
+
+        let skip = ((target_secs - decoded_start).max(0.0) * sr).round() as u64;
+        self.skip_frames = Some(skip);
+
+        let late = (decoded_start - target_secs).max(0.0);
+        if late > 0.0 && self.align_to_timeline {
+            let frames = (late * sr).round() as usize;
+            self.pending.extend(std::iter::repeat_n(0.0, frames));
+            self.report.timing.leading_silence = Duration::from_secs_f64(late);
+            self.start_time = target;
+        } else {
+            self.start_time = target + Duration::from_secs_f64(late);
+        }
+
+        self.frame_limit = self
+            .range
+            .and_then(|r| r.end)
+            .map(|end| duration_to_frames(end.saturating_sub(self.start_time), self.sample_rate));
+        if self.frame_limit == Some(0) {
+            self.finished = true;
+        }
+        self.trim_queued(0);
+    }
+}
+
+/// Extension used to pick a demuxer, Matroska unless the path says otherwise.
+fn hint_extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("mkv")
+        .to_ascii_lowercase()
+}
+
+/// Sniffs the EBML magic, leaving the source at the start.
+fn is_matroska<R: Read + Seek>(source: &mut R) -> std::io::Result<bool> {
+    let mut magic = [0u8; 4];
+    let matroska = match source.read_exact(&mut magic) {
+        Ok(()) => u32::from_be_bytes(magic) == ebml::ID_EBML,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+    source.seek(SeekFrom::Start(0))?;
+    Ok(matroska)
+}
+
+fn find_track_timing(
+    timings: Result<Vec<MkvTrackTiming>>,
+    track_id: u32,
+) -> Result<Option<MkvTrackTiming>> {
+    Ok(timings?.into_iter().find(|t| t.number == track_id as u64))
+}
+
+impl Iterator for AudioStream {
+    type Item = Result<AudioChunk, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished && self.pending.is_empty() && self.loudness_meter.is_none() {
+            return None;
+        }
+        if self.control.is_cancelled() {
+            self.finished = true;
+            self.pending.clear();
+            self.loudness_meter = None;
+            return Some(Err(DecodeError::Cancelled));
+        }
+
+        if let Err(e) = self.fill_pending() {
+            self.finished = true;
+            self.pending.clear();
+            return Some(Err(e));
+        }
+
+        if self.pending.is_empty() {
+            if let Some(meter) = self.loudness_meter.take() {
+                self.report.loudness = Some(meter.finish());
+            }
+            return None;
+        }
+
+        let len = self.chunk_frames.min(self.pending.len());
+        let samples: Vec<f32> = self.pending.drain(..len).collect();
+        if let Some(meter) = self.loudness_meter.as_mut() {
+            meter.push(&samples);
+        }
+        let chunk = AudioChunk {
+            start_frame: self.emitted_frames,
+            samples,
+        };
+        self.emitted_frames += len as u64;
+        self.report.frames = self.emitted_frames;
+
+        self.control.report(Progress {
+            stage: Stage::Decoding,
+            file: Some(self.report.path.as_path()).filter(|p| !p.as_os_str().is_empty()),
+            processed: Amount::Time(frames_to_duration(self.emitted_frames, self.sample_rate)),
+            total: self
+                .expected_frames()
+                .map(|n| Amount::Time(frames_to_duration(n, self.sample_rate))),
+        });
+
+        Some(Ok(chunk))
+    }
+}
+
+/// Averages all channels of `decoded` into `out`.
+fn push_downmixed(
+    sample_buf: &mut Option<SampleBuffer<f32>>,
+    out: &mut VecDeque<f32>,
+    decoded: AudioBufferRef,
+) {
+    let spec = *decoded.spec();
+    let capacity = decoded.capacity() as u64;
+    let buf = match sample_buf {
+        Some(buf) if buf.capacity() >= decoded.capacity() * spec.channels.count() => buf,
+        _ => sample_buf.insert(SampleBuffer::new(capacity, spec)),
+    };
+    buf.copy_interleaved_ref(decoded);
+
+    let channels = spec.channels.count().max(1);
+    if channels == 1 {
+        out.extend(buf.samples().iter().copied());
+    } else {
+        let scale = 1.0 / channels as f32;
+        out.extend(
+            buf.samples()
+                .chunks_exact(channels)
+                .map(|frame| frame.iter().sum::<f32>() * scale),
+        );
+    }
+}
+
+/// Drains the metadata queue of `format`, logging every tag.
+fn log_metadata_revisions(format: &mut dyn FormatReader) {
+    let mut metadata = format.metadata();
+    while !metadata.is_latest() {
+        // Pop the old head of the metadata queue.
+        let Some(md): Option<MetadataRevision> = metadata.pop() else {
+            break;
+        };
+
+        for tag in md.tags() {
+            log::info!("Key: {}, Value: {}", tag.key, tag.value);
+        }
+
+        for vendordata in md.vendor_data() {
+            log::info!(
+                "ident: {}, data: {:?}",
+                vendordata.ident,
+                vendordata.data.first()
+            );
+        }
+
+        for visual in md.visuals() {
+            for tag in visual.tags.iter() {
+                log::info!("Key: {}, Value: {}", tag.key, tag.value);
+            }
+        }
+    }
+}
+
+/// Overlapping fixed-size frames on top of an [`AudioStream`], for STFT style consumers.
+pub struct FramedAudioStream {
+    stream: AudioStream,
+    frame_len: usize,
+    hop: usize,
+    window: VecDeque<f32>,
+    /// Stream frame index of `window[0]`.
+    window_start: u64,
+    /// Samples still to drop before the next frame, left over when `hop`
+    /// is longer than the buffered window.
+    skip: usize,
+    stream_done: bool,
+}
+
+/// One analysis frame produced by [`FramedAudioStream`].
+#[derive(Debug, Clone)]
+pub struct AudioFrame {
+    pub start_frame: u64,
+    pub samples: Vec<f32>,
+}
+
+impl FramedAudioStream {
+    pub fn new(stream: AudioStream, frame_len: usize, hop: usize) -> Self {
+        assert!(
+            frame_len > 0 && hop > 0,
+            "frame_len and hop must be non-zero"
+        );
+        Self {
+            stream,
+            frame_len,
+            hop,
+            window: VecDeque::with_capacity(frame_len * 2),
+            window_start: 0,
+            skip: 0,
+            stream_done: false,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.stream.sample_rate()
+    }
+
+    pub fn report(&self) -> &DecodeReport {
+        self.stream.report()
+    }
+}
+
+impl Iterator for FramedAudioStream {
+    type Item = Result<AudioFrame, DecodeError>;
+
+    /// The trailing frame is zero padded so every decoded sample is covered.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let skipped = self.skip.min(self.window.len());
+            self.window.drain(..skipped);
+            self.window_start += skipped as u64;
+            self.skip -= skipped;
+            if self.stream_done || (self.skip == 0 && self.window.len() >= self.frame_len) {
+                break;
+            }
+            match self.stream.next() {
+                Some(Ok(chunk)) => self.window.extend(chunk.samples),
+                Some(Err(e)) => {
+                    self.stream_done = true;
+                    return Some(Err(e));
+                }
+                None => self.stream_done = true,
+            }
+        }
+
+        if self.window.is_empty() {
+            return None;
+        }
+
+        let mut samples: Vec<f32> = self.window.iter().take(self.frame_len).copied().collect();
+        samples.resize(self.frame_len, 0.0);
+        let frame = AudioFrame {
+            start_frame: self.window_start,
+            samples,
+        };
+
+        let advance = self.hop.min(self.window.len());
+        self.window.drain(..advance);
+        self.window_start += advance as u64;
+        self.skip = self.hop - advance;
+
+        Some(Ok(frame))
+    }
+}
+
 pub fn decode_audio_with_ffmpeg_f32(path: &str) -> Result<(Vec<f32>, u32)> {
     // Step 1: Extract sample rate using ffprobe
     let output = Command::new("ffprobe")
@@ -171,335 +1200,58 @@ pub fn decode_audio_with_ffmpeg_u8(path: &str) -> Result<(Vec<u8>, u32)> {
 
 // returns a array with samples and the sample rate
 pub fn decode_samples_audio_only_from_file(path: &Path) -> Result<(Vec<f32>, u32)> {
-    log::info!("###############################");
-    log::info!(
-        "[0/6] Start fetching samples for <{}>",
-        &path.to_string_lossy()
-    );
-
-    let src = File::open(&path).with_context(|| format!("Failed to open: {}", path.display()))?;
-
-    log::info!("[1/6] Creating MediaSourceStream");
-    let mss = MediaSourceStream::new(Box::new(src), Default::default());
-
-    let mut hint = Hint::new();
-    hint.with_extension("mkv");
-
-    let meta_opts: MetadataOptions = Default::default();
-    let fmt_opts: FormatOptions = Default::default();
-
-    log::info!("[2/6] Creating ProbeResult");
-    let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
-
-    // Get the instantiated format reader.
-    let mut format = probed.format;
-
-    for t in format.tracks() {
-        let codec = t.codec_params.codec;
-        eprintln!("Track index = {}, codec = {:?}", t.id, codec);
-    }
-
-    log::info!("[3/6] Finding Track");
-    // Find the first audio track with a known (decodeable) codec.
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| {
-            let codec = t.codec_params.codec;
-            codec != CODEC_TYPE_NULL && SUPPORTED_AUDIO_CODECS.contains(&codec)
-        })
-        .with_context(|| format!("No supported audio track found in file: {}", path.display()))?;
-
-    // Store the track identifier, it will be used to filter packets.
-    let track_id = track.id;
-
-    let mut sample_rate: u32 = 0;
-    match track.codec_params.sample_rate {
-        Some(v) => sample_rate = v,
-        None => log::info!("sample_rate failed"),
-    }
-    let mut n_frames: u64 = 0;
-    match track.codec_params.n_frames {
-        Some(v) => n_frames = v,
-        None => log::info!("n_frames failed"),
-    }
-    // let mut time_base: TimeBase = TimeBase::default();
-    // match track.codec_params.time_base {
-    //     Some(v) => time_base = v,
-    //     None => log::info!("time_base failed"),
-    // }
-
-    log::info!("[4/6] Creating Decoder for Track {}", track_id);
-    let dec_opts: DecoderOptions = Default::default();
-    let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &dec_opts)?;
-
-    // The decode loop.
-    log::info!("[5/6] Decoding...");
-    let mut ret_samples = Vec::with_capacity(n_frames as usize);
-    while let Ok(packet) = format.next_packet() {
-        if packet.track_id() != track_id {
-            continue;
-        }
-
-        if let Ok(decoded) = decoder.decode(&packet) {
-            if let AudioBufferRef::F32(buf) = decoded {
-                ret_samples.extend_from_slice(buf.chan(0));
-            } else {
-                anyhow::bail!("Non-f32 sample format not supported");
-            }
-        }
-    }
-
-    log::info!(
-        "[6/6] Finished fetching samples for <{}>",
-        &path.to_string_lossy(),
-    );
+    let audio = decode_audio(path, &DecodeOptions::default())
+        .with_context(|| format!("Failed to decode: {}", path.display()))?;
 
-    Ok((ret_samples, sample_rate))
+    Ok((audio.samples, audio.sample_rate))
 }
 
 // returns a array with samples and the sample rate
+//
+// Same as `decode_samples_audio_only_from_file`, the stream already falls
+// through to the next track when no decoder exists for a codec.
 pub fn decode_samples_only_from_file(path: &Path) -> Result<(Vec<f32>, u32)> {
-    log::info!("###############################");
-    log::info!(
-        "[0/6] Start fetching samples for <{}>",
-        &path.to_string_lossy()
-    );
-
-    let src = File::open(&path).with_context(|| format!("Failed to open: {}", path.display()))?;
-
-    log::info!("[1/6] Creating MediaSourceStream");
-    let mss = MediaSourceStream::new(Box::new(src), Default::default());
-
-    let mut hint = Hint::new();
-    hint.with_extension("mkv");
-
-    let meta_opts: MetadataOptions = Default::default();
-    let fmt_opts: FormatOptions = Default::default();
-
-    log::info!("[2/6] Creating ProbeResult");
-    let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
-
-    // Get the instantiated format reader.
-    let mut format = probed.format;
-
-    log::info!("[3/6] Finding Track");
-    // Find the first audio track with a known (decodeable) codec.
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .with_context(|| "Could not find track")?;
-
-    // Store the track identifier, it will be used to filter packets.
-    let track_id = track.id;
-
-    let mut sample_rate: u32 = 0;
-    match track.codec_params.sample_rate {
-        Some(v) => sample_rate = v,
-        None => log::info!("sample_rate failed"),
-    }
-    let mut n_frames: u64 = 0;
-    match track.codec_params.n_frames {
-        Some(v) => n_frames = v,
-        None => log::info!("n_frames failed"),
-    }
-    // let mut time_base: TimeBase = TimeBase::default();
-    // match track.codec_params.time_base {
-    //     Some(v) => time_base = v,
-    //     None => log::info!("time_base failed"),
-    // }
-
-    log::info!("[4/6] Creating Decoder for Track {}", track_id);
-    let dec_opts: DecoderOptions = Default::default();
-    let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &dec_opts)?;
-
-    // The decode loop.
-    log::info!("[5/6] Decoding...");
-    let mut ret_samples = Vec::with_capacity(n_frames as usize);
-    while let Ok(packet) = format.next_packet() {
-        if packet.track_id() != track_id {
-            continue;
-        }
-
-        if let Ok(decoded) = decoder.decode(&packet) {
-            if let AudioBufferRef::F32(buf) = decoded {
-                ret_samples.extend_from_slice(buf.chan(0));
-            } else {
-                anyhow::bail!("Non-f32 sample format not supported");
-            }
-        }
-    }
-
-    log::info!(
-        "[6/6] Finished fetching samples for <{}>",
-        &path.to_string_lossy(),
-    );
-
-    Ok((ret_samples, sample_rate))
+    decode_samples_audio_only_from_file(path)
 }
 
 // returns a array with samples and the sample rate
 pub fn decode_samples_from_file(path: &Path, read_metadata: bool) -> Result<(Vec<f32>, u32)> {
     log::info!("###############################");
+    log::info!("Start fetching samples for <{}>", &path.to_string_lossy());
+
+    let options = DecodeOptions {
+        log_metadata: read_metadata,
+        control: JobControl::with_progress(LogProgress::default()),
+        ..Default::default()
+    };
+    let mut stream = AudioStream::open_with(path, &options)
+        .with_context(|| format!("Failed to open: {}", path.display()))?;
+    let sample_rate = stream.sample_rate();
+    let n_frames = stream.expected_frames().unwrap_or_default();
     log::info!(
-        "[0/6] Start fetching samples for <{}>",
-        &path.to_string_lossy()
+        "Gathering Samples ({}s)",
+        frames_to_duration(n_frames, sample_rate).as_secs()
     );
 
-    let src = File::open(&path)?;
-
-    log::info!("[1/6] Creating MediaSourceStream");
-    let mss = MediaSourceStream::new(Box::new(src), Default::default());
-
-    let mut hint = Hint::new();
-    hint.with_extension("mkv");
-
-    let meta_opts: MetadataOptions = Default::default();
-    let fmt_opts: FormatOptions = Default::default();
-
-    log::info!("[2/6] Creating ProbeResult");
-    let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
-
-    // Get the instantiated format reader.
-    let mut format = probed.format;
-
-    log::info!("[3/6] Finding Track");
-    // Find the first audio track with a known (decodeable) codec.
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .with_context(|| "Could not find track")?;
-
-    // Store the track identifier, it will be used to filter packets.
-    let track_id = track.id;
-
-    let mut sample_rate: u32 = 0;
-    match track.codec_params.sample_rate {
-        Some(v) => sample_rate = v,
-        None => log::info!("sample_rate failed"),
-    }
-    let mut n_frames: u64 = 0;
-    match track.codec_params.n_frames {
-        Some(v) => n_frames = v,
-        None => log::info!("n_frames failed"),
-    }
-    let mut time_base: TimeBase = TimeBase::default();
-    match track.codec_params.time_base {
-        Some(v) => time_base = v,
-        None => log::info!("time_base failed"),
-    }
-
-    log::info!("[4/6] Creating Decoder for Track {}", track_id);
-    let dec_opts: DecoderOptions = Default::default();
-    let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &dec_opts)?;
-
-    // The decode loop.
-    let time_duration = time_base.calc_time(n_frames).seconds;
-    log::info!("[5/6] Gathering Samples ({}s)", time_duration);
-
-    // Determine 10 counter steps to show print
-    let mut package_print_steps: Vec<u64> = Vec::new();
-    let mut package_print_current_step: usize = 0;
-    {
-        let package_print_steps_num = 10;
-        package_print_steps.reserve(package_print_steps_num + 1);
-        for i in 0..package_print_steps_num {
-            let n = (i as f32) / (package_print_steps_num as f32);
-            let v = n as f32 * time_duration as f32;
-            package_print_steps.push(v as u64);
-        }
-        package_print_steps.push(time_duration as u64);
-    }
-
-    let mut timestamp_counter: u64 = 0;
     let mut ret_samples = Vec::with_capacity(n_frames as usize);
-    loop {
-        // Get the next packet from the media format.
-        let packet = match format.next_packet() {
-            Ok(packet) => packet,
-            Err(Error::ResetRequired) => {
-                // The track list has been changed. Re-examine it and create a new set of decoders,
-                // then restart the decode loop. This is an advanced feature and it is not
-                // unreasonable to consider this "the end." As of v0.5.0, the only usage of this is
-                // for chained OGG physical streams.
-                unimplemented!();
-            }
-            Err(err) => {
-                // Handling this the real way somehow?
-                if err.to_string() == String::from("end of stream") {
-                    break;
-                }
-                // A unrecoverable error occured, halt decoding.
-                panic!("{}", err);
-            }
-        };
-
-        timestamp_counter += packet.dur;
-        let cur_time = time_base.calc_time(timestamp_counter).seconds;
-        if package_print_steps[package_print_current_step] == cur_time {
-            // prevent it from being printed again
-            package_print_steps[package_print_current_step] = 0;
-            package_print_current_step =
-                (package_print_current_step + 1) % package_print_steps.len();
-
-            // Show % progress
-            log::info!(
-                "[{}%] Gathering Samples",
-                100.0 * (cur_time as f32) / (time_duration as f32)
-            );
-        }
-
-        // Consume any new metadata that has been read since the last packet.
-        if read_metadata {
-            while !format.metadata().is_latest() {
-                // Pop the old head of the metadata queue.
-                let _metadata = format.metadata().pop();
-
-                let md: MetadataRevision = _metadata.with_context(|| "MetadataRevision Failed")?;
-
-                for tag in md.tags() {
-                    log::info!("Key: {}, Value: {}", tag.key, tag.value);
-                }
-
-                for vendordata in md.vendor_data() {
-                    log::info!("ident: {}, data: {}", vendordata.ident, vendordata.data[0]);
-                }
-
-                for visual in md.visuals() {
-                    for tag in visual.clone().tags {
-                        log::info!("Key: {}, Value: {}", tag.key, tag.value);
-                    }
-                }
-
-                // Consume the new metadata at the head of the metadata queue.
-            }
-        }
-
-        // If the packet does not belong to the selected track, skip over it.
-        if packet.track_id() != track_id {
-            continue;
-        }
+    for chunk in stream.by_ref() {
+        let chunk = chunk.with_context(|| format!("Failed to decode: {}", path.display()))?;
+        ret_samples.extend_from_slice(&chunk.samples);
+    }
 
-        // Decode the packet into audio samples.
-        let decoded = decoder.decode(&packet)?;
-        match decoded {
-            AudioBufferRef::F32(buf) => {
-                for &sample in buf.chan(0) {
-                    ret_samples.push(sample);
-                }
-            }
-            _ => {
-                // Repeat for the different sample formats.
-                anyhow::bail!("unimplemented");
-            }
-        }
+    let report = stream.into_report();
+    if !report.is_clean() {
+        log::warn!(
+            "<{}> decoded with {} gap(s), {:?} in total",
+            path.display(),
+            report.gaps.len(),
+            report.total_gap_duration()
+        );
     }
     log::info!(
-        "[6/6] Finished fetching samples for <{}> ({})",
+        "Finished fetching samples for <{}> ({}s)",
         &path.to_string_lossy(),
-        time_base.calc_time(timestamp_counter).seconds
+        frames_to_duration(report.frames, sample_rate).as_secs_f64()
     );
 
     Ok((ret_samples, sample_rate))
@@ -507,9 +1259,10 @@ pub fn decode_samples_from_file(path: &Path, read_metadata: bool) -> Result<(Vec
 
 use sonogram::*;
 pub static S_SPECTROGRAM_NUM_BINS: usize = 2048;
+/// Takes `data` by value so the samples are moved into sonogram instead of copied.
 pub fn save_spectrograph_as_png(
     path: &PathBuf,
-    data: &Vec<f32>,
+    data: Vec<f32>,
     sample_rate: u32,
     out_dim: [usize; 2],
 ) {
@@ -523,7 +1276,7 @@ pub fn save_spectrograph_as_png(
     );
 
     let mut spectrobuilder = SpecOptionsBuilder::new(S_SPECTROGRAM_NUM_BINS)
-        .load_data_from_memory_f32(data.clone(), sample_rate)
+        .load_data_from_memory_f32(data, sample_rate)
         .build()
         .unwrap();
     let mut spectogram = spectrobuilder.compute();
@@ -531,7 +1284,7 @@ pub fn save_spectrograph_as_png(
     let mut gradient = ColourGradient::black_white_theme();
     spectogram
         .to_png(
-            &png_file,
+            png_file,
             FrequencyScale::Linear,
             &mut gradient,
             out_dim[0],
@@ -541,3 +1294,48 @@ pub fn save_spectrograph_as_png(
 
     log::info!("[1/1] Finish spectrograph to png");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Mono 16-bit WAV whose sample `i` has the value `i`.
+    fn counting_wav(len: usize) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+        for i in 0..len {
+            writer.write_sample(i as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn frames_stay_on_hop_grid_when_hop_exceeds_frame_len() {
+        let options = DecodeOptions {
+            chunk_frames: 3,
+            ..Default::default()
+        };
+        let stream = AudioStream::from_source(
+            Box::new(Cursor::new(counting_wav(35))),
+            &SourceHint::extension("wav"),
+            &options,
+        )
+        .unwrap();
+
+        let frames: Vec<AudioFrame> = stream.framed(4, 10).map(Result::unwrap).collect();
+        let starts: Vec<u64> = frames.iter().map(|f| f.start_frame).collect();
+        assert_eq!(starts, [0, 10, 20, 30]);
+        for frame in &frames {
+            let first = (frame.samples[0] * 32768.0).round() as u64;
+            assert_eq!(first, frame.start_frame);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Band-limited sample rate conversion of mono buffers.
+
+use std::f64::consts::PI;
+
+/// Filter half-width in input samples at unity ratio, widened when downsampling.
+const HALF_TAPS: usize = 16;
+/// Cutoff relative to the lower Nyquist frequency, leaves room for the transition band.
+const CUTOFF: f64 = 0.95;
+/// Polyphase tables are precomputed up to this many phases, odd ratios compute taps per sample.
+const MAX_TABLE_PHASES: u64 = 4096;
+
+/// Resamples `samples` from `from` Hz to `to` Hz with a Blackman windowed sinc.
+///
+/// The output has `ceil(len * to / from)` samples and starts at the same
+/// instant as the input, so track offsets carry over unchanged.
+pub fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || samples.is_empty() || from == 0 || to == 0 {
+        return samples.to_vec();
+    }
+
+    let g = gcd(from as u64, to as u64);
+    let up = to as u64 / g;
+    let down = from as u64 / g;
+
+    // Fraction of the input band kept, below 1 when downsampling.
+    let ratio = (to as f64 / from as f64).min(1.0);
+    let cutoff = CUTOFF * ratio;
+    let half = (HALF_TAPS as f64 / ratio).ceil() as usize;
+
+    let table: Option<Vec<Vec<f32>>> = (up <= MAX_TABLE_PHASES).then(|| {
+        (0..up)
+            .map(|phase| taps(phase as f64 / up as f64, half, cutoff))
+            .collect()
+    });
+
+    let out_len = (samples.len() as u64 * up).div_ceil(down) as usize;
+    let mut out = Vec::with_capacity(out_len);
+    for k in 0..out_len as u64 {
+        let pos = k * down;
+        let n = (pos / up) as isize;
+        let phase = pos % up;
+
+        let computed;
+        let weights = match &table {
+            Some(table) => &table[phase as usize],
+            None => {
+                computed = taps(phase as f64 / up as f64, half, cutoff);
+                &computed
+            }
+        };
+
+        // weights[j] belongs to input sample n - half + 1 + j.
+        let first = n - half as isize + 1;
+        let mut acc = 0f32;
+        for (j, w) in weights.iter().enumerate() {
+            let i = first + j as isize;
+            if i >= 0 && (i as usize) < samples.len() {
+                acc += samples[i as usize] * w;
+            }
+        }
+        out.push(acc);
+    }
+    out
+}
+
+/// Filter taps for an output sample `frac` input samples after input sample `n`,
+/// covering inputs `n - half + 1 ..= n + half`.
+fn taps(frac: f64, half: usize, cutoff: f64) -> Vec<f32> {
+    let span = half as f64;
+    (0..2 * half)
+        .map(|j| {
+            let d = frac - (j as f64 - span + 1.0);
+            let x = cutoff * d;
+            let sinc = if x.abs() < 1e-12 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            let w = d / span;
+            let window = if w.abs() >= 1.0 {
+                0.0
+            } else {
+                0.42 + 0.5 * (PI * w).cos() + 0.08 * (2.0 * PI * w).cos()
+            };
+            (cutoff * sinc * window) as f32
+        })
+        .collect()
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
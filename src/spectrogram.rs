@@ -1,93 +1,542 @@
+pub mod columns;
+pub mod format;
+pub mod render;
+pub mod stats;
+
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::File,
-    io::Write,
-    path::{Path, PathBuf},
+    ops::{Index, IndexMut, Range},
+    path::Path,
+    time::Duration,
 };
 
-use sonogram::{SonogramError, SpecOptionsBuilder, Spectrogram};
+use sonogram::{SpecOptionsBuilder, Spectrogram};
+
+use symphonia::core::io::MediaSource;
 
+use crate::cache;
+use crate::progress::{Amount, JobControl, Progress, Stage};
 use crate::sound::{
-    decode_audio_with_ffmpeg_f32, decode_samples_audio_only_from_file,
-    decode_samples_only_from_file,
+    DecodeOptions, DecodedAudio, SourceHint, TimeRange, decode_audio, decode_audio_from_source,
+    decode_audio_with_ffmpeg_f32,
 };
+use crate::spectrogram::columns::TimeGrid;
+use crate::spectrogram::format::{SpectrogramFile, SpectrogramSource};
+use crate::spectrogram::stats::{BinStats, Normalization};
+use crate::stft::{SpectrogramConfig, Stft};
 
 pub const SPECTROGRAM_WIDTH: usize = 512;
 pub const SPECTROGRAM_HEIGHT: usize = 512;
-pub fn generate_spectrogram(path: &PathBuf, num_spectrogram_bins: usize) -> Result<Spectrogram> {
-    let (samples, sample_rate) = decode_audio_with_ffmpeg_f32(&path.to_str().unwrap())?;
 
+/// Frequency axis of a [`ZaoSpectrogram`].
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+pub enum SpectrogramScale {
+    #[default]
+    Linear,
+    Log,
+    /// Mel filterbank energies, see [`crate::stft::FrequencyAxis::Mel`].
+    Mel,
+    /// Geometrically spaced bins, see [`crate::stft::FrequencyAxis::ConstantQ`].
+    ConstantQ,
+}
+
+impl From<sonogram::FrequencyScale> for SpectrogramScale {
+    fn from(scale: sonogram::FrequencyScale) -> Self {
+        match scale {
+            sonogram::FrequencyScale::Linear => SpectrogramScale::Linear,
+            sonogram::FrequencyScale::Log => SpectrogramScale::Log,
+        }
+    }
+}
+
+/// Mel and constant-Q axes are already perceptual, they map to `Log`.
+impl From<SpectrogramScale> for sonogram::FrequencyScale {
+    fn from(scale: SpectrogramScale) -> Self {
+        match scale {
+            SpectrogramScale::Linear => sonogram::FrequencyScale::Linear,
+            SpectrogramScale::Log | SpectrogramScale::Mel | SpectrogramScale::ConstantQ => {
+                sonogram::FrequencyScale::Log
+            }
+        }
+    }
+}
+
+/// What the values of a [`ZaoSpectrogram`] measure.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+pub enum SpectrogramValues {
+    #[default]
+    Magnitude,
+    Power,
+    Decibels,
+}
+
+/// Owned spectrogram, `height` rows of `width` time columns, row-major.
+///
+/// Row 0 is the highest frequency, the same orientation as sonogram and
+/// as the rendered image. `sample_rate`, `n_fft` and `hop` are 0 when
+/// unknown, e.g. for legacy files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct ZaoSpectrogram {
+    data: Vec<f32>,
+    width: usize,
+    height: usize,
+    pub frequency_scale: SpectrogramScale,
+    pub values: SpectrogramValues,
+    /// Sample rate of the analysed audio.
+    pub sample_rate: u32,
+    /// FFT size the columns were computed with.
+    pub n_fft: usize,
+    /// Samples between the starts of two columns, fractional once resized.
+    pub hop: f64,
+    /// Analysis the spectrogram was computed with, `None` for sonogram output
+    /// and once the columns are resampled to another hop.
+    #[serde(default)]
+    pub config: Option<SpectrogramConfig>,
+}
+
+impl ZaoSpectrogram {
+    /// Fails unless `data` holds exactly `width * height` values.
+    pub fn new(
+        data: Vec<f32>,
+        width: usize,
+        height: usize,
+        frequency_scale: SpectrogramScale,
+        sample_rate: u32,
+        hop: f64,
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            width.checked_mul(height) == Some(data.len()),
+            "spectrogram data has {} values, expected {width} x {height}",
+            data.len()
+        );
+        Ok(Self {
+            data,
+            width,
+            height,
+            frequency_scale,
+            values: SpectrogramValues::default(),
+            sample_rate,
+            n_fft: 0,
+            hop,
+            config: None,
+        })
+    }
+
+    pub fn zeros(width: usize, height: usize) -> Self {
+        Self {
+            data: vec![0.0; width * height],
+            width,
+            height,
+            frequency_scale: SpectrogramScale::Linear,
+            values: SpectrogramValues::default(),
+            sample_rate: 0,
+            n_fft: 0,
+            hop: 0.0,
+            config: None,
+        }
+    }
+
+    /// Copies the output of sonogram, whose hop is its step size.
+    pub fn from_sonogram(spectrogram: &Spectrogram, sample_rate: u32, hop: usize) -> Self {
+        let (width, height) = spectrogram.shape();
+        let data = (0..height)
+            .flat_map(|row| spectrogram.row_iter(row))
+            .copied()
+            .collect();
+        Self {
+            data,
+            width,
+            height,
+            frequency_scale: SpectrogramScale::Linear,
+            values: SpectrogramValues::Magnitude,
+            sample_rate,
+            n_fft: height * 2,
+            hop: hop as f64,
+            config: None,
+        }
+    }
+
+    pub fn to_sonogram(&self) -> Spectrogram {
+        Spectrogram::from_raw(&self.data, self.width, self.height)
+            .expect("length is checked on construction")
+    }
+
+    /// Resampled to `width` x `height` in dB, the representation written by
+    /// [`save_spectrogram`]. Magnitudes and powers are converted by sonogram
+    /// as before, dB values are only resampled.
+    pub fn resized_db(&self, width: usize, height: usize) -> Self {
+        let data = match self.values {
+            SpectrogramValues::Magnitude => {
+                self.to_sonogram()
+                    .to_buffer(sonogram::FrequencyScale::Linear, width, height)
+            }
+            SpectrogramValues::Power => {
+                let magnitude: Vec<f32> = self.data.iter().map(|p| p.max(0.0).sqrt()).collect();
+                Spectrogram::from_raw(&magnitude, self.width, self.height)
+                    .expect("length is checked on construction")
+                    .to_buffer(sonogram::FrequencyScale::Linear, width, height)
+            }
+            SpectrogramValues::Decibels => {
+                render::resample(&self.data, self.width, self.height, width, height)
+            }
+        };
+        Self {
+            data,
+            width,
+            height,
+            values: SpectrogramValues::Decibels,
+            hop: self.hop * self.width as f64 / width.max(1) as f64,
+            config: None,
+            ..self.clone_metadata()
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// `(width, height)`, like [`Spectrogram::shape`].
+    pub fn shape(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub fn into_data(self) -> Vec<f32> {
+        self.data
+    }
+
+    pub fn get(&self, row: usize, column: usize) -> Option<f32> {
+        (row < self.height && column < self.width).then(|| self.data[row * self.width + column])
+    }
+
+    pub fn row(&self, row: usize) -> &[f32] {
+        &self.data[row * self.width..(row + 1) * self.width]
+    }
+
+    pub fn column(&self, column: usize) -> Vec<f32> {
+        assert!(column < self.width, "column {column} out of range");
+        self.data[column..]
+            .iter()
+            .step_by(self.width)
+            .copied()
+            .collect()
+    }
+
+    /// Copy of the columns in `columns`, keeping the metadata.
+    pub fn slice_columns(&self, columns: Range<usize>) -> Self {
+        assert!(
+            columns.start <= columns.end && columns.end <= self.width,
+            "columns {columns:?} out of range for width {}",
+            self.width
+        );
+        let data = self
+            .data
+            .chunks_exact(self.width.max(1))
+            .flat_map(|row| &row[columns.clone()])
+            .copied()
+            .collect();
+        Self {
+            data,
+            width: columns.len(),
+            ..self.clone_metadata()
+        }
+    }
+
+    /// Copy of the rows in `rows`, keeping the metadata.
+    pub fn slice_rows(&self, rows: Range<usize>) -> Self {
+        assert!(
+            rows.start <= rows.end && rows.end <= self.height,
+            "rows {rows:?} out of range for height {}",
+            self.height
+        );
+        Self {
+            data: self.data[rows.start * self.width..rows.end * self.width].to_vec(),
+            height: rows.len(),
+            ..self.clone_metadata()
+        }
+    }
+
+    /// Time of the start of `column` relative to the first column, `None` if the hop is unknown.
+    pub fn column_time(&self, column: usize) -> Option<Duration> {
+        (self.sample_rate > 0 && self.hop > 0.0)
+            .then(|| Duration::from_secs_f64(column as f64 * self.hop / self.sample_rate as f64))
+    }
+
+    fn clone_metadata(&self) -> Self {
+        Self {
+            data: Vec::new(),
+            width: self.width,
+            height: self.height,
+            frequency_scale: self.frequency_scale,
+            values: self.values,
+            sample_rate: self.sample_rate,
+            n_fft: self.n_fft,
+            hop: self.hop,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Indexed by `(row, column)`.
+impl Index<(usize, usize)> for ZaoSpectrogram {
+    type Output = f32;
+
+    fn index(&self, (row, column): (usize, usize)) -> &f32 {
+        assert!(row < self.height && column < self.width);
+        &self.data[row * self.width + column]
+    }
+}
+
+impl IndexMut<(usize, usize)> for ZaoSpectrogram {
+    fn index_mut(&mut self, (row, column): (usize, usize)) -> &mut f32 {
+        assert!(row < self.height && column < self.width);
+        &mut self.data[row * self.width + column]
+    }
+}
+pub fn generate_spectrogram(path: &Path, num_spectrogram_bins: usize) -> Result<ZaoSpectrogram> {
+    generate_spectrogram_with_control(path, num_spectrogram_bins, &JobControl::default())
+}
+
+/// [`generate_spectrogram`] reporting each stage to `control`. Cancellation
+/// is checked between decoding and the FFT, neither step is interrupted.
+///
+/// The decoded audio is taken from the [`cache::global_cache`] when one is installed.
+pub fn generate_spectrogram_with_control(
+    path: &Path,
+    num_spectrogram_bins: usize,
+    control: &JobControl,
+) -> Result<ZaoSpectrogram> {
+    let DecodedAudio {
+        samples,
+        sample_rate,
+        ..
+    } = decode_cached(path, control)?;
+
+    control.check()?;
+    report_stage(control, path, Stage::Spectrogram, 0);
     let mut spectrobuilder = SpecOptionsBuilder::new(num_spectrogram_bins)
         .load_data_from_memory_f32(samples, sample_rate)
         .build()
         .map_err(|e| anyhow::anyhow!("failed to build spectrogram: {:?}", e))?;
 
     let spectrogram = spectrobuilder.compute();
+    report_stage(control, path, Stage::Spectrogram, 1);
+
+    Ok(ZaoSpectrogram::from_sonogram(
+        &spectrogram,
+        sample_rate,
+        num_spectrogram_bins,
+    ))
+}
+
+/// Like [`generate_spectrogram_with_control`] but computed by the in-crate
+/// [`Stft`] as described by `config`, which is kept in the result.
+pub fn generate_spectrogram_with_config(
+    path: &Path,
+    config: &SpectrogramConfig,
+    control: &JobControl,
+) -> Result<ZaoSpectrogram> {
+    config.validate()?;
+    let audio = decode_cached(path, control)?;
+
+    control.check()?;
+    report_stage(control, path, Stage::Spectrogram, 0);
+    let spectrogram = Stft::new(audio.sample_rate, config.clone())?.process(&audio.samples);
+    report_stage(control, path, Stage::Spectrogram, 1);
 
     Ok(spectrogram)
 }
 
-const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
+/// Mono f32 audio of `path` by ffmpeg, from the [`cache::global_cache`] when one is installed.
+fn decode_cached(path: &Path, control: &JobControl) -> Result<DecodedAudio> {
+    control.check()?;
+    report_stage(control, path, Stage::Decoding, 0);
+    let decode = || -> Result<DecodedAudio> {
+        let (samples, sample_rate) = decode_audio_with_ffmpeg_f32(path.to_str().unwrap())?;
+        Ok(DecodedAudio {
+            samples,
+            sample_rate,
+            ..Default::default()
+        })
+    };
+    let audio = match cache::global_cache() {
+        Some(cache) => cache.get_or_insert_with(path, &"ffmpeg-f32le-mono", decode)?,
+        None => decode()?,
+    };
+    report_stage(control, path, Stage::Decoding, 1);
+    Ok(audio)
+}
+
+fn report_stage(control: &JobControl, path: &Path, stage: Stage, done: u64) {
+    control.report(Progress {
+        stage,
+        file: Some(path),
+        processed: Amount::Items(done),
+        total: Some(Amount::Items(1)),
+    })
+}
+
+/// Spectrogram of a part of a file, `offset` is the track time of its first column.
+pub struct SpectrogramWindow {
+    pub spectrogram: ZaoSpectrogram,
+    pub offset: Duration,
+    pub duration: Duration,
+}
+
+/// Like [`generate_spectrogram`] but only decodes `range` of the audio track.
+pub fn generate_spectrogram_window(
+    path: impl AsRef<Path>,
+    num_spectrogram_bins: usize,
+    range: TimeRange,
+) -> Result<SpectrogramWindow> {
+    let audio = decode_audio(path, &DecodeOptions::default().with_range(range))?;
+    spectrogram_window_from_audio(audio, num_spectrogram_bins)
+}
+
+/// Spectrogram of an in-memory or otherwise file-less source, `options.range`
+/// limits it to a window like [`generate_spectrogram_window`].
+pub fn generate_spectrogram_from_source(
+    source: Box<dyn MediaSource>,
+    hint: &SourceHint,
+    num_spectrogram_bins: usize,
+    options: &DecodeOptions,
+) -> Result<SpectrogramWindow> {
+    let audio = decode_audio_from_source(source, hint, options)?;
+    spectrogram_window_from_audio(audio, num_spectrogram_bins)
+}
+
+fn spectrogram_window_from_audio(
+    audio: DecodedAudio,
+    num_spectrogram_bins: usize,
+) -> Result<SpectrogramWindow> {
+    let offset = audio.offset;
+    let duration = audio.duration();
+    let sample_rate = audio.sample_rate;
+
+    // sonogram steps by the FFT size unless told otherwise.
+    let mut spectrobuilder = SpecOptionsBuilder::new(num_spectrogram_bins)
+        .load_data_from_memory_f32(audio.samples, sample_rate)
+        .build()
+        .map_err(|e| anyhow::anyhow!("failed to build spectrogram: {:?}", e))?;
+    let spectrogram = spectrobuilder.compute();
+
+    Ok(SpectrogramWindow {
+        spectrogram: ZaoSpectrogram::from_sonogram(&spectrogram, sample_rate, num_spectrogram_bins),
+        offset,
+        duration,
+    })
+}
+
+/// Writes `spectrogram` resized to `width` x `height` in dB, in the
+/// versioned [`format`]. `source` is the analysed file, its content hash
+/// is stored in the header.
 pub fn save_spectrogram(
-    spectrogram: &Spectrogram,
+    spectrogram: &ZaoSpectrogram,
     width: usize,
     height: usize,
+    source: Option<&Path>,
+    path: impl AsRef<Path>,
+) -> Result<()> {
+    write_resized(
+        spectrogram.resized_db(width, height),
+        spectrogram,
+        source,
+        path,
+    )
+}
+
+/// Writes `resized`, recording the analysis of `original` it was resized from.
+fn write_resized(
+    resized: ZaoSpectrogram,
+    original: &ZaoSpectrogram,
+    source: Option<&Path>,
     path: impl AsRef<Path>,
 ) -> Result<()> {
-    let spectrogram_buffer = spectrogram.to_buffer(sonogram::FrequencyScale::Linear, width, height);
-    let data = (width, height, spectrogram_buffer);
-    let bytes = bincode::encode_to_vec(data, BINCODE_CONFIG)?;
+    let source = source.map(SpectrogramSource::from_file).transpose()?;
+    let mut file = SpectrogramFile::new(resized, source);
+    file.analysis = original.config.clone();
 
     if path.as_ref().exists() {
         println!("{}, already exists", path.as_ref().to_string_lossy());
     }
-    let mut file = File::create(path.as_ref())
-        .with_context(|| format!("Failed to create file at {}", path.as_ref().display()))?;
-    file.write_all(&bytes)?;
-
-    Ok(())
+    file.save(path)
 }
 
-pub fn load_spectrogram(
+/// Like [`save_spectrogram`] but with the columns on `grid` instead of
+/// stretched to a fixed width, so a column covers the same time in every file.
+pub fn save_spectrogram_on_grid(
+    spectrogram: &ZaoSpectrogram,
+    grid: &TimeGrid,
+    height: usize,
+    source: Option<&Path>,
     path: impl AsRef<Path>,
-    out_width: &mut usize,
-    out_height: &mut usize,
-) -> Result<Spectrogram> {
-    let bytes = std::fs::read(&path).with_context(|| format!("{}", path.as_ref().display()))?;
-    let (width, height, buffer): (usize, usize, Vec<f32>) =
-        bincode::decode_from_slice(&bytes, BINCODE_CONFIG).map(|(v, _)| v)?;
-
-    #[allow(unused_mut)]
-    let mut spectrogram = create_spectrogram_unsafe(buffer, width, height);
-
-    let mut test_path = path.as_ref().to_path_buf();
-    test_path.set_extension("png");
-    spectrogram.to_png(
-        &test_path,
-        sonogram::FrequencyScale::Log,
-        &mut sonogram::ColourGradient::black_white_theme(),
-        width,
-        height,
-    )?;
-
-    *out_width = width;
-    *out_height = height;
-    Ok(spectrogram)
+) -> Result<()> {
+    anyhow::ensure!(
+        grid.seconds_per_column > 0.0,
+        "seconds per column must be positive"
+    );
+    let duration = spectrogram
+        .duration()
+        .context("spectrogram has no sample rate or hop")?;
+    // Audio shorter than half a column still gets one.
+    let width = ((duration.as_secs_f64() / grid.seconds_per_column).round() as usize).max(1);
+    let mut db = spectrogram.resized_db(width, height);
+    db.hop = grid.seconds_per_column * db.sample_rate as f64;
+    let db = match grid.columns {
+        Some(columns) => db.fit_width(columns, grid.pad_value),
+        None => db,
+    };
+    write_resized(db, spectrogram, source, path)
 }
 
-pub fn create_spectrogram_unsafe(spec: Vec<f32>, width: usize, height: usize) -> Spectrogram {
-    #[allow(dead_code)]
-    struct SpectrogramRepr {
-        spec: Vec<f32>,
-        width: usize,
-        height: usize,
-    }
-
-    let repr = SpectrogramRepr {
-        spec,
-        width,
-        height,
-    };
+/// Loads and validates a spectrogram file, legacy files without a header
+/// are read as dB on a linear axis. See [`format::migrate_spectrogram_file`]
+/// to upgrade them and [`render`] to draw them.
+pub fn load_spectrogram(path: impl AsRef<Path>) -> Result<ZaoSpectrogram> {
+    Ok(SpectrogramFile::load(path)?.spectrogram)
+}
 
-    unsafe { std::mem::transmute::<SpectrogramRepr, Spectrogram>(repr) }
+/// [`load_spectrogram`] normalized by dataset statistics, see [`stats`].
+pub fn load_spectrogram_normalized(
+    path: impl AsRef<Path>,
+    stats: &BinStats,
+    method: Normalization,
+) -> Result<ZaoSpectrogram> {
+    let mut spectrogram = load_spectrogram(path)?;
+    stats.normalize(&mut spectrogram, method)?;
+    Ok(spectrogram)
 }
@@ -0,0 +1,180 @@
+//! Time calibration of spectrogram columns.
+//!
+//! Column `i` covers `[i, i + 1) * seconds_per_column` from the start of the
+//! analysed audio, or of the label window for windowed labels. A
+//! [`TimeGrid`] fixes the seconds per column and optionally the number of
+//! columns, so every file of a dataset shares one time axis.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use super::ZaoSpectrogram;
+use super::render::resample;
+use crate::ai_labels::ZaoaiLabel;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeGrid {
+    pub seconds_per_column: f64,
+    /// Columns of the result, shorter audio is padded at the end and longer
+    /// audio cropped. `None` keeps every column.
+    pub columns: Option<usize>,
+    /// Value of padded columns, `None` for the spectrogram's minimum.
+    pub pad_value: Option<f32>,
+}
+
+impl Default for TimeGrid {
+    /// Half a second per column, 30 minute episodes.
+    fn default() -> Self {
+        Self {
+            seconds_per_column: 0.5,
+            columns: Some(3600),
+            pad_value: None,
+        }
+    }
+}
+
+impl TimeGrid {
+    /// `columns` columns spanning exactly `duration`.
+    pub fn for_duration(duration: Duration, columns: usize) -> Self {
+        Self {
+            seconds_per_column: duration.as_secs_f64() / columns.max(1) as f64,
+            columns: Some(columns),
+            pad_value: None,
+        }
+    }
+
+    /// Time covered by the grid, `None` without a fixed number of columns.
+    pub fn duration(&self) -> Option<Duration> {
+        self.columns
+            .map(|c| Duration::from_secs_f64(c as f64 * self.seconds_per_column))
+    }
+}
+
+impl ZaoSpectrogram {
+    /// `None` if the sample rate or hop is unknown.
+    pub fn seconds_per_column(&self) -> Option<f64> {
+        (self.sample_rate > 0 && self.hop > 0.0).then(|| self.hop / self.sample_rate as f64)
+    }
+
+    /// Time covered by all columns.
+    pub fn duration(&self) -> Option<Duration> {
+        self.seconds_per_column()
+            .map(|s| Duration::from_secs_f64(s * self.width() as f64))
+    }
+
+    /// Resamples the columns to `seconds_per_column`. The last column may
+    /// cover up to half a column more or less than the audio, and there is
+    /// always at least one.
+    pub fn with_seconds_per_column(&self, seconds_per_column: f64) -> Result<Self> {
+        anyhow::ensure!(
+            seconds_per_column > 0.0,
+            "seconds per column must be positive"
+        );
+        let duration = self
+            .duration()
+            .context("spectrogram has no sample rate or hop")?;
+        let width = ((duration.as_secs_f64() / seconds_per_column).round() as usize).max(1);
+        let data = resample(
+            self.data(),
+            self.width(),
+            self.height(),
+            width,
+            self.height(),
+        );
+
+        Ok(Self {
+            data,
+            width,
+            hop: seconds_per_column * self.sample_rate as f64,
+            config: None,
+            ..self.clone_metadata()
+        })
+    }
+
+    /// Pads the end with `pad_value`, or the minimum, or crops to `width` columns.
+    pub fn fit_width(&self, width: usize, pad_value: Option<f32>) -> Self {
+        if width <= self.width() {
+            return self.slice_columns(0..width);
+        }
+        let pad =
+            pad_value.unwrap_or_else(|| self.data().iter().copied().fold(f32::INFINITY, f32::min));
+        let pad = if pad.is_finite() { pad } else { 0.0 };
+
+        let mut data = Vec::with_capacity(width * self.height());
+        for row in 0..self.height() {
+            data.extend_from_slice(self.row(row));
+            data.resize((row + 1) * width, pad);
+        }
+        Self {
+            data,
+            width,
+            ..self.clone_metadata()
+        }
+    }
+
+    pub fn to_time_grid(&self, grid: &TimeGrid) -> Result<Self> {
+        let spectrogram = self.with_seconds_per_column(grid.seconds_per_column)?;
+        Ok(match grid.columns {
+            Some(columns) => spectrogram.fit_width(columns, grid.pad_value),
+            None => spectrogram,
+        })
+    }
+
+    /// Fractional column position of `seconds`, column `i` starts at `i`.
+    pub fn seconds_to_column(&self, seconds: f64) -> Option<f64> {
+        self.seconds_per_column().map(|s| seconds / s)
+    }
+
+    pub fn column_to_seconds(&self, column: f64) -> Option<f64> {
+        self.seconds_per_column().map(|s| column * s)
+    }
+
+    /// Column position of a normalized label value, relative to `duration`,
+    /// the episode or label window length.
+    pub fn normalized_to_column(&self, normalized: f64, duration: Duration) -> Option<f64> {
+        self.seconds_to_column(normalized * duration.as_secs_f64())
+    }
+
+    pub fn column_to_normalized(&self, column: f64, duration: Duration) -> Option<f64> {
+        let secs = duration.as_secs_f64();
+        self.column_to_seconds(column)
+            .filter(|_| secs > 0.0)
+            .map(|s| s / secs)
+    }
+
+    /// Fraction of every column inside the opening of `label`, in `[0, 1]`.
+    ///
+    /// Column 0 is the start of `label.window` for windowed labels and of the
+    /// file otherwise. Zero everywhere for labels without an opening.
+    pub fn label_coverage(&self, label: &ZaoaiLabel) -> Result<Vec<f32>> {
+        let (Some(start), Some(end)) = (label.opening_start_time, label.opening_end_time) else {
+            return self.interval_coverage(0.0, 0.0);
+        };
+        let origin = label.window.map_or(0.0, |w| w.start.as_secs_f64());
+        self.interval_coverage(start.as_secs_f64() - origin, end.as_secs_f64() - origin)
+    }
+
+    /// Fraction of every column inside `[start, end)` seconds from column 0.
+    pub fn interval_coverage(&self, start: f64, end: f64) -> Result<Vec<f32>> {
+        let seconds_per_column = self
+            .seconds_per_column()
+            .context("spectrogram has no sample rate or hop")?;
+        let (start, end) = (start / seconds_per_column, end / seconds_per_column);
+        Ok((0..self.width())
+            .map(|i| {
+                let overlap = end.min(i as f64 + 1.0) - start.max(i as f64);
+                overlap.clamp(0.0, 1.0) as f32
+            })
+            .collect())
+    }
+
+    /// Columns at least half inside the opening of `label`, see [`Self::label_coverage`].
+    pub fn label_mask(&self, label: &ZaoaiLabel) -> Result<Vec<bool>> {
+        Ok(self
+            .label_coverage(label)?
+            .into_iter()
+            .map(|c| c >= 0.5)
+            .collect())
+    }
+}
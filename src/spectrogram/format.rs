@@ -0,0 +1,265 @@
+//! Versioned spectrogram file format.
+//!
+//! Layout, little endian:
+//!   magic "ZSPC", format version u32, header JSON length u32, header JSON,
+//!   payload length u64, `width * height` f32 values row-major,
+//!   SHA-256 of everything before it.
+//!
+//! The header records the analysis parameters, including the
+//! [`SpectrogramConfig`] of STFT output, and the content hash of the
+//! source file, so spectrograms from different runs can be told apart.
+//! Files from before the format existed are a bare bincode
+//! `(width, height, Vec<f32>)` of dB values and are read by
+//! [`read_legacy_spectrogram`].
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{SpectrogramScale, SpectrogramValues, ZaoSpectrogram};
+use crate::hash::ContentHash;
+use crate::stft::SpectrogramConfig;
+
+/// Bumped whenever the layout or the header changes incompatibly.
+pub const SPECTROGRAM_FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"ZSPC";
+const CHECKSUM_LEN: usize = 32;
+const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
+
+/// File the spectrogram was computed from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpectrogramSource {
+    pub path: PathBuf,
+    pub hash: ContentHash,
+}
+
+impl SpectrogramSource {
+    /// Hashes the content of `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let hash = ContentHash::of_file(path)
+            .with_context(|| format!("Failed to hash {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            hash,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpectrogramFile {
+    pub spectrogram: ZaoSpectrogram,
+    /// `None` for spectrograms of file-less sources and for migrated legacy files.
+    pub source: Option<SpectrogramSource>,
+    /// Format version the file was read with, 0 for legacy files.
+    pub version: u32,
+    /// Analysis the spectrogram was computed with. Unlike
+    /// [`ZaoSpectrogram::config`] it is kept when the columns were resized
+    /// before saving.
+    pub analysis: Option<SpectrogramConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SpectrogramHeader {
+    width: usize,
+    height: usize,
+    frequency_scale: SpectrogramScale,
+    values: SpectrogramValues,
+    sample_rate: u32,
+    n_fft: usize,
+    hop: f64,
+    #[serde(default)]
+    config: Option<SpectrogramConfig>,
+    source: Option<SpectrogramSource>,
+}
+
+impl SpectrogramFile {
+    pub fn new(spectrogram: ZaoSpectrogram, source: Option<SpectrogramSource>) -> Self {
+        Self {
+            analysis: spectrogram.config.clone(),
+            spectrogram,
+            source,
+            version: SPECTROGRAM_FORMAT_VERSION,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let s = &self.spectrogram;
+        let header = serde_json::to_vec(&SpectrogramHeader {
+            width: s.width(),
+            height: s.height(),
+            frequency_scale: s.frequency_scale,
+            values: s.values,
+            sample_rate: s.sample_rate,
+            n_fft: s.n_fft,
+            hop: s.hop,
+            config: self.analysis.clone(),
+            source: self.source.clone(),
+        })?;
+
+        let mut bytes = Vec::with_capacity(4 + 4 + 4 + header.len() + 8 + s.data().len() * 4 + 32);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&SPECTROGRAM_FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&(s.data().len() as u64 * 4).to_le_bytes());
+        for value in s.data() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let checksum = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&checksum);
+        Ok(bytes)
+    }
+
+    /// Parses and validates a file in the current format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        anyhow::ensure!(is_spectrogram_file(bytes), "not a spectrogram file");
+        anyhow::ensure!(
+            bytes.len() >= 12 + CHECKSUM_LEN,
+            "spectrogram file is truncated"
+        );
+
+        let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        anyhow::ensure!(
+            Sha256::digest(body).as_slice() == checksum,
+            "spectrogram file checksum mismatch"
+        );
+
+        let mut reader = body;
+        let _magic = take(&mut reader, 4)?;
+        let version = u32::from_le_bytes(take(&mut reader, 4)?.try_into()?);
+        anyhow::ensure!(
+            version == SPECTROGRAM_FORMAT_VERSION,
+            "spectrogram format version {version}, expected {SPECTROGRAM_FORMAT_VERSION}"
+        );
+
+        let header_len = u32::from_le_bytes(take(&mut reader, 4)?.try_into()?) as usize;
+        let header: SpectrogramHeader = serde_json::from_slice(take(&mut reader, header_len)?)
+            .context("Invalid spectrogram header")?;
+
+        let payload_len = u64::from_le_bytes(take(&mut reader, 8)?.try_into()?);
+        anyhow::ensure!(
+            payload_len == reader.len() as u64
+                && header
+                    .width
+                    .checked_mul(header.height)
+                    .map(|n| n as u64 * 4)
+                    == Some(payload_len),
+            "spectrogram payload does not match {} x {}",
+            header.width,
+            header.height
+        );
+        let data = reader
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+
+        let mut spectrogram = ZaoSpectrogram::new(
+            data,
+            header.width,
+            header.height,
+            header.frequency_scale,
+            header.sample_rate,
+            header.hop,
+        )?;
+        spectrogram.values = header.values;
+        spectrogram.n_fft = header.n_fft;
+        spectrogram.config = header.config.clone().filter(|c| c.hop as f64 == header.hop);
+
+        Ok(Self {
+            spectrogram,
+            source: header.source,
+            version,
+            analysis: header.config,
+        })
+    }
+
+    /// Writes to a temporary file first, so readers never see half a file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let bytes = self.to_bytes()?;
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        let write = || -> Result<()> {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            writer.write_all(&bytes)?;
+            writer.flush()?;
+            fs::rename(&tmp, path)?;
+            Ok(())
+        };
+        write()
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp);
+            })
+            .with_context(|| format!("Failed to create file at {}", path.display()))
+    }
+
+    /// Reads a file in the current format, or a legacy tuple file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).with_context(|| format!("{}", path.display()))?;
+        let file = if is_spectrogram_file(&bytes) {
+            Self::from_bytes(&bytes)
+        } else {
+            read_legacy_spectrogram(&bytes)
+        };
+        file.with_context(|| format!("Failed to load spectrogram {}", path.display()))
+    }
+}
+
+pub fn is_spectrogram_file(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Reads the bare bincode `(width, height, Vec<f32>)` written before the
+/// format was versioned. The values are dB on a linear frequency axis, the
+/// analysis parameters were not recorded.
+pub fn read_legacy_spectrogram(bytes: &[u8]) -> Result<SpectrogramFile> {
+    let ((width, height, data), read): ((usize, usize, Vec<f32>), usize) =
+        bincode::decode_from_slice(bytes, BINCODE_CONFIG).context("Not a legacy spectrogram")?;
+    anyhow::ensure!(
+        read == bytes.len(),
+        "trailing bytes after legacy spectrogram"
+    );
+
+    let mut spectrogram =
+        ZaoSpectrogram::new(data, width, height, SpectrogramScale::Linear, 0, 0.0)?;
+    spectrogram.values = SpectrogramValues::Decibels;
+    Ok(SpectrogramFile {
+        spectrogram,
+        source: None,
+        version: 0,
+        analysis: None,
+    })
+}
+
+/// Rewrites a legacy file at `path` in the current format, recording
+/// `source` if known. Returns `false` if the file already was current.
+pub fn migrate_spectrogram_file(
+    path: impl AsRef<Path>,
+    source: Option<SpectrogramSource>,
+) -> Result<bool> {
+    let path = path.as_ref();
+    let file = SpectrogramFile::load(path)?;
+    if file.version == SPECTROGRAM_FORMAT_VERSION {
+        return Ok(false);
+    }
+    SpectrogramFile {
+        source,
+        version: SPECTROGRAM_FORMAT_VERSION,
+        ..file
+    }
+    .save(path)?;
+    Ok(true)
+}
+
+fn take<'a>(reader: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    anyhow::ensure!(reader.len() >= n, "spectrogram file is truncated");
+    let (head, rest) = reader.split_at(n);
+    *reader = rest;
+    Ok(head)
+}
@@ -0,0 +1,325 @@
+//! Rendering spectrograms to PNG images.
+//!
+//! Values are converted to dB relative to the maximum, optionally mapped to
+//! a log frequency axis, resampled to the output size and scaled into the
+//! dB range before being written as grayscale or through a colormap.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use png::HasParameters;
+use sonogram::ColourGradient;
+
+use super::{SpectrogramScale, SpectrogramValues, ZaoSpectrogram, load_spectrogram};
+
+/// Colour gradients of sonogram, from low to high values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Colormap {
+    #[default]
+    BlackWhite,
+    WhiteBlack,
+    Audacity,
+    Rainbow,
+    LightDark,
+    /// sonogram's default theme.
+    Classic,
+}
+
+impl Colormap {
+    fn gradient(self) -> ColourGradient {
+        let mut gradient = match self {
+            Colormap::BlackWhite => ColourGradient::black_white_theme(),
+            Colormap::WhiteBlack => ColourGradient::white_black_theme(),
+            Colormap::Audacity => ColourGradient::audacity_theme(),
+            Colormap::Rainbow => ColourGradient::rainbow_theme(),
+            Colormap::LightDark => ColourGradient::light_dark_theme(),
+            Colormap::Classic => ColourGradient::default_theme(),
+        };
+        gradient.set_min(0.0);
+        gradient.set_max(1.0);
+        gradient
+    }
+}
+
+impl FromStr for Colormap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "black-white" | "blackwhite" => Colormap::BlackWhite,
+            "white-black" | "whiteblack" => Colormap::WhiteBlack,
+            "audacity" => Colormap::Audacity,
+            "rainbow" => Colormap::Rainbow,
+            "light-dark" | "lightdark" => Colormap::LightDark,
+            "classic" | "default" => Colormap::Classic,
+            _ => anyhow::bail!("unknown colormap '{s}'"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Gray8,
+    /// Full precision of the dB range, e.g. for training input.
+    Gray16,
+    /// 8-bit RGBA through the colormap.
+    Colour(Colormap),
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        ImageFormat::Colour(Colormap::default())
+    }
+}
+
+/// Accepts `gray8`, `gray16` or a colormap name.
+impl FromStr for ImageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "gray8" | "grey8" => ImageFormat::Gray8,
+            "gray16" | "grey16" => ImageFormat::Gray16,
+            colormap => ImageFormat::Colour(colormap.parse()?),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderOptions {
+    /// Axis of the image, `Log` remaps linear spectrograms. Log spectrograms
+    /// are drawn as they are.
+    pub frequency_scale: SpectrogramScale,
+    pub format: ImageFormat,
+    /// dB values mapped to black and white, `None` for the image's min and max.
+    pub db_range: Option<(f32, f32)>,
+    /// `(width, height)` of the image, `None` for the spectrogram's shape.
+    pub size: Option<(usize, usize)>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            frequency_scale: SpectrogramScale::Log,
+            format: ImageFormat::default(),
+            db_range: None,
+            size: None,
+        }
+    }
+}
+
+/// Pixel intensities in `[0, 1]`, row 0 at the top.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<f32>,
+}
+
+impl ZaoSpectrogram {
+    pub fn render(&self, options: &RenderOptions) -> RenderedImage {
+        let (width, height) = options.size.unwrap_or(self.shape());
+        let mut db = to_db(self);
+        if options.frequency_scale == SpectrogramScale::Log
+            && self.frequency_scale == SpectrogramScale::Linear
+        {
+            db = log_frequency_rows(&db, self.width(), self.height());
+        }
+        if self.shape() != (width, height) {
+            db = resample(&db, self.width(), self.height(), width, height);
+        }
+
+        let (min, max) = options.db_range.unwrap_or_else(|| {
+            db.iter()
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &v| {
+                    (lo.min(v), hi.max(v))
+                })
+        });
+        let span = (max - min).max(f32::EPSILON);
+        let pixels = db
+            .iter()
+            .map(|&v| ((v - min) / span).clamp(0.0, 1.0))
+            .collect();
+
+        RenderedImage {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn to_png_bytes(&self, options: &RenderOptions) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        write_png(&self.render(options), options.format, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn to_png(&self, path: impl AsRef<Path>, options: &RenderOptions) -> Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create file at {}", path.display()))?;
+        write_png(&self.render(options), options.format, BufWriter::new(file))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Renders the spectrogram file at `input` to a PNG at `output`.
+pub fn render_spectrogram_file(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    options: &RenderOptions,
+) -> Result<()> {
+    load_spectrogram(input)?.to_png(output, options)
+}
+
+pub fn write_png(image: &RenderedImage, format: ImageFormat, writer: impl Write) -> Result<()> {
+    let (colour_type, bit_depth, data) = match format {
+        ImageFormat::Gray8 => (
+            png::ColorType::Grayscale,
+            png::BitDepth::Eight,
+            image
+                .pixels
+                .iter()
+                .map(|&p| (p * u8::MAX as f32).round() as u8)
+                .collect::<Vec<u8>>(),
+        ),
+        ImageFormat::Gray16 => (
+            png::ColorType::Grayscale,
+            png::BitDepth::Sixteen,
+            image
+                .pixels
+                .iter()
+                .flat_map(|&p| ((p * u16::MAX as f32).round() as u16).to_be_bytes())
+                .collect(),
+        ),
+        ImageFormat::Colour(colormap) => {
+            let gradient = colormap.gradient();
+            (
+                png::ColorType::RGBA,
+                png::BitDepth::Eight,
+                image
+                    .pixels
+                    .iter()
+                    .flat_map(|&p| {
+                        let c = gradient.get_colour(p);
+                        [c.r, c.g, c.b, c.a]
+                    })
+                    .collect(),
+            )
+        }
+    };
+
+    let mut encoder = png::Encoder::new(writer, image.width as u32, image.height as u32);
+    encoder.set(colour_type).set(bit_depth);
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| anyhow::anyhow!("failed to write png header: {e}"))?;
+    writer
+        .write_image_data(&data)
+        .map_err(|e| anyhow::anyhow!("failed to write png data: {e}"))?;
+    Ok(())
+}
+
+/// dB relative to the maximum, floored 120 dB below it.
+fn to_db(spectrogram: &ZaoSpectrogram) -> Vec<f32> {
+    let data = spectrogram.data();
+    let factor = match spectrogram.values {
+        SpectrogramValues::Decibels => return data.to_vec(),
+        SpectrogramValues::Magnitude => 20.0,
+        SpectrogramValues::Power => 10.0,
+    };
+    let max = data.iter().fold(0.0f32, |m, &v| m.max(v)).max(1e-20);
+    data.iter()
+        .map(|&v| (factor * (v.max(0.0) / max).max(1e-20).log10()).max(-120.0))
+        .collect()
+}
+
+/// Remaps rows onto a log frequency axis between the first bin and the top.
+fn log_frequency_rows(data: &[f32], width: usize, height: usize) -> Vec<f32> {
+    if height < 2 {
+        return data.to_vec();
+    }
+    let top = (height - 1) as f32;
+    let mut out = vec![0.0; data.len()];
+    for row in 0..height {
+        // Bins count up from the bottom row.
+        let position = (height - 1 - row) as f32 / top;
+        let bin = (top + 1.0).powf(position) - 1.0;
+        let (lower, frac) = (bin.floor() as usize, bin.fract());
+        let upper = (lower + 1).min(height - 1);
+        let (a, b) = (height - 1 - lower, height - 1 - upper);
+        for column in 0..width {
+            out[row * width + column] =
+                data[a * width + column] * (1.0 - frac) + data[b * width + column] * frac;
+        }
+    }
+    out
+}
+
+/// Resamples a row-major `width` x `height` grid to `new_width` x `new_height`.
+pub(crate) fn resample(
+    data: &[f32],
+    width: usize,
+    height: usize,
+    new_width: usize,
+    new_height: usize,
+) -> Vec<f32> {
+    let data = resample_axis(data, width, height, new_width, true);
+    resample_axis(&data, new_width, height, new_height, false)
+}
+
+/// Resamples one axis by averaging the overlapped input cells, so shrinking
+/// does not skip columns. `columns` picks the axis.
+fn resample_axis(
+    data: &[f32],
+    width: usize,
+    height: usize,
+    new_len: usize,
+    columns: bool,
+) -> Vec<f32> {
+    let (len, other) = if columns {
+        (width, height)
+    } else {
+        (height, width)
+    };
+    let at = |i: usize, j: usize| {
+        if columns {
+            data[j * width + i]
+        } else {
+            data[i * width + j]
+        }
+    };
+    let (out_width, out_height) = if columns {
+        (new_len, height)
+    } else {
+        (width, new_len)
+    };
+    let mut out = vec![0.0; out_width * out_height];
+    if len == 0 || new_len == 0 {
+        return out;
+    }
+
+    let scale = len as f64 / new_len as f64;
+    for k in 0..new_len {
+        let (start, end) = (k as f64 * scale, (k + 1) as f64 * scale);
+        let first = start.floor() as usize;
+        let last = (end.ceil() as usize).min(len);
+        for j in 0..other {
+            let mut sum = 0.0;
+            for i in first..last {
+                let overlap = (end.min(i as f64 + 1.0) - start.max(i as f64)).max(0.0);
+                sum += at(i, j) as f64 * overlap;
+            }
+            let value = (sum / scale) as f32;
+            if columns {
+                out[j * out_width + k] = value;
+            } else {
+                out[k * out_width + j] = value;
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,261 @@
+//! Per-frequency-bin statistics over a dataset of spectrograms.
+//!
+//! [`compute_directory_stats`] streams the saved spectrograms of a
+//! directory tree, one file in memory at a time, and accumulates the mean and
+//! variance of every row with Welford's method. The result is saved next to
+//! the dataset and applied at load time by [`BinStats::normalize`].
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use super::{SpectrogramScale, SpectrogramValues, ZaoSpectrogram, load_spectrogram};
+use crate::container::Container;
+use crate::file::list_dir_all;
+use crate::progress::{Amount, JobControl, Progress, Stage};
+
+/// Bumped whenever [`BinStats`] changes incompatibly.
+pub const STATS_FORMAT_VERSION: u32 = 1;
+
+const CONTAINER: Container = Container {
+    magic: b"ZBST",
+    version: STATS_FORMAT_VERSION,
+    name: "statistics",
+};
+
+/// How [`BinStats::normalize`] maps values.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Normalization {
+    /// Zero mean and unit variance per bin.
+    #[default]
+    Standardize,
+    /// `[0, 1]` between the minimum and maximum of each bin.
+    MinMax,
+}
+
+/// Statistics of every row, row 0 being the highest frequency as in
+/// [`ZaoSpectrogram`].
+#[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode)]
+pub struct BinStats {
+    pub frequency_scale: SpectrogramScale,
+    pub values: SpectrogramValues,
+    /// Spectrograms accumulated.
+    pub files: u64,
+    /// Values accumulated per bin, the total number of columns.
+    pub count: u64,
+    pub mean: Vec<f64>,
+    /// Sum of squared differences from the mean.
+    m2: Vec<f64>,
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+}
+
+impl BinStats {
+    pub fn new(
+        height: usize,
+        frequency_scale: SpectrogramScale,
+        values: SpectrogramValues,
+    ) -> Self {
+        Self {
+            frequency_scale,
+            values,
+            files: 0,
+            count: 0,
+            mean: vec![0.0; height],
+            m2: vec![0.0; height],
+            min: vec![f32::INFINITY; height],
+            max: vec![f32::NEG_INFINITY; height],
+        }
+    }
+
+    /// Empty statistics shaped like `spectrogram`.
+    pub fn for_spectrogram(spectrogram: &ZaoSpectrogram) -> Self {
+        Self::new(
+            spectrogram.height(),
+            spectrogram.frequency_scale,
+            spectrogram.values,
+        )
+    }
+
+    pub fn height(&self) -> usize {
+        self.mean.len()
+    }
+
+    /// Adds every column of `spectrogram`.
+    pub fn update(&mut self, spectrogram: &ZaoSpectrogram) -> Result<()> {
+        self.check(spectrogram)?;
+        for row in 0..self.height() {
+            let (mut n, mut mean, mut m2) = (self.count, self.mean[row], self.m2[row]);
+            let (mut min, mut max) = (self.min[row], self.max[row]);
+            for &x in spectrogram.row(row) {
+                n += 1;
+                let delta = x as f64 - mean;
+                mean += delta / n as f64;
+                m2 += delta * (x as f64 - mean);
+                min = min.min(x);
+                max = max.max(x);
+            }
+            (self.mean[row], self.m2[row]) = (mean, m2);
+            (self.min[row], self.max[row]) = (min, max);
+        }
+        self.count += spectrogram.width() as u64;
+        self.files += 1;
+        Ok(())
+    }
+
+    /// Population variance of every bin, zero before any value is added.
+    pub fn variance(&self) -> Vec<f64> {
+        self.m2
+            .iter()
+            .map(|m2| {
+                if self.count > 0 {
+                    m2 / self.count as f64
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    pub fn std(&self) -> Vec<f64> {
+        self.variance().into_iter().map(f64::sqrt).collect()
+    }
+
+    /// Applies `method` in place. Bins without spread map to zero.
+    pub fn normalize(&self, spectrogram: &mut ZaoSpectrogram, method: Normalization) -> Result<()> {
+        self.check(spectrogram)?;
+        anyhow::ensure!(self.count > 0, "normalization statistics are empty");
+        let (offset, scale): (Vec<f64>, Vec<f64>) = match method {
+            Normalization::Standardize => (self.mean.clone(), self.std()),
+            Normalization::MinMax => self
+                .min
+                .iter()
+                .zip(&self.max)
+                .map(|(&min, &max)| (min as f64, (max - min) as f64))
+                .unzip(),
+        };
+
+        let width = spectrogram.width();
+        for (row, values) in spectrogram
+            .data_mut()
+            .chunks_exact_mut(width.max(1))
+            .enumerate()
+        {
+            let (offset, scale) = (offset[row], scale[row]);
+            for v in values {
+                *v = if scale > f64::EPSILON {
+                    ((*v as f64 - offset) / scale) as f32
+                } else {
+                    0.0
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, spectrogram: &ZaoSpectrogram) -> Result<()> {
+        anyhow::ensure!(
+            spectrogram.height() == self.height(),
+            "spectrogram has {} bins, statistics have {}",
+            spectrogram.height(),
+            self.height()
+        );
+        anyhow::ensure!(
+            spectrogram.frequency_scale == self.frequency_scale
+                && spectrogram.values == self.values,
+            "spectrogram is {:?} {:?}, statistics are {:?} {:?}",
+            spectrogram.frequency_scale,
+            spectrogram.values,
+            self.frequency_scale,
+            self.values
+        );
+        Ok(())
+    }
+}
+
+/// Statistics of every file with `extension` in `dir` and its subdirectories.
+pub fn compute_directory_stats(dir: impl AsRef<Path>, extension: &str) -> Result<BinStats> {
+    compute_directory_stats_with_control(dir, extension, &JobControl::default())
+}
+
+/// [`compute_directory_stats`] reporting finished files to `control`.
+pub fn compute_directory_stats_with_control(
+    dir: impl AsRef<Path>,
+    extension: &str,
+    control: &JobControl,
+) -> Result<BinStats> {
+    let dir = dir.as_ref();
+    let mut paths: Vec<PathBuf> = list_dir_all(dir, false)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .into_iter()
+        .filter(|p| p.extension().is_some_and(|e| e == extension))
+        .collect();
+    paths.sort();
+
+    let mut stats: Option<BinStats> = None;
+    for (i, path) in paths.iter().enumerate() {
+        control.check()?;
+        let spectrogram = load_spectrogram(path)?;
+        stats
+            .get_or_insert_with(|| BinStats::for_spectrogram(&spectrogram))
+            .update(&spectrogram)
+            .with_context(|| format!("{}", path.display()))?;
+        control.report(Progress {
+            stage: Stage::Spectrogram,
+            file: Some(path),
+            processed: Amount::Items(i as u64 + 1),
+            total: Some(Amount::Items(paths.len() as u64)),
+        });
+    }
+    stats.with_context(|| format!("No .{extension} spectrograms in {}", dir.display()))
+}
+
+pub fn save_stats(stats: &BinStats, path: impl AsRef<Path>) -> Result<()> {
+    CONTAINER.save(stats, path)
+}
+
+pub fn load_stats(path: impl AsRef<Path>) -> Result<BinStats> {
+    CONTAINER.load(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spectrogram::format::SpectrogramFile;
+    use std::fs;
+
+    #[test]
+    fn directory_stats_include_nested_series() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [
+            ("top.zspc", 1.0),
+            ("show_a/ep01.zspc", 2.0),
+            ("show_a/season_2/ep01.zspc", 3.0),
+            ("show_b/ep01.zspc", 6.0),
+        ];
+        for (name, value) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let spectrogram = ZaoSpectrogram::new(
+                vec![value; 8],
+                4,
+                2,
+                SpectrogramScale::Linear,
+                16_000,
+                512.0,
+            )
+            .unwrap();
+            SpectrogramFile::new(spectrogram, None).save(path).unwrap();
+        }
+        fs::write(dir.path().join("show_b/notes.txt"), "not a spectrogram").unwrap();
+
+        let stats = compute_directory_stats(dir.path(), "zspc").unwrap();
+        assert_eq!((stats.files, stats.count), (4, 16));
+        assert_eq!(stats.mean, [3.0, 3.0]);
+        assert_eq!((stats.min[0], stats.max[0]), (1.0, 6.0));
+
+        let path = dir.path().join("stats.zbst");
+        save_stats(&stats, &path).unwrap();
+        assert_eq!(load_stats(&path).unwrap(), stats);
+    }
+}
@@ -0,0 +1,365 @@
+//! Short-time Fourier transform producing [`ZaoSpectrogram`]s, configured
+//! by a [`SpectrogramConfig`] that is kept with the result.
+//!
+//! Besides linear FFT bins the frequency axis can be mel filterbank
+//! energies or constant-Q bins, see [`FrequencyAxis`].
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+
+use crate::filterbank::{ConstantQKernel, MelFilterbank, MelScale};
+use crate::spectrogram::{SpectrogramScale, SpectrogramValues, ZaoSpectrogram};
+
+/// Magnitudes below this are treated as this before taking logs.
+const AMIN: f32 = 1e-5;
+
+/// Analysis window, all periodic so overlapping frames sum evenly.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub enum WindowFunction {
+    #[default]
+    Hann,
+    Hamming,
+    Blackman,
+    /// Larger `beta` trades main lobe width for lower side lobes.
+    Kaiser {
+        beta: f32,
+    },
+    Rectangular,
+}
+
+impl WindowFunction {
+    pub fn coefficients(self, len: usize) -> Vec<f32> {
+        let n = len as f64;
+        (0..len)
+            .map(|i| {
+                let x = 2.0 * PI * i as f64 / n;
+                let w = match self {
+                    WindowFunction::Hann => 0.5 - 0.5 * x.cos(),
+                    WindowFunction::Hamming => 0.54 - 0.46 * x.cos(),
+                    WindowFunction::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos(),
+                    WindowFunction::Kaiser { beta } => {
+                        let beta = beta as f64;
+                        let r = 2.0 * i as f64 / n - 1.0;
+                        bessel_i0(beta * (1.0 - r * r).max(0.0).sqrt()) / bessel_i0(beta)
+                    }
+                    WindowFunction::Rectangular => 1.0,
+                };
+                w as f32
+            })
+            .collect()
+    }
+}
+
+/// Rows of the spectrogram.
+#[derive(
+    Debug, Clone, PartialEq, Default, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub enum FrequencyAxis {
+    /// `n_fft / 2 + 1` FFT bins.
+    #[default]
+    Linear,
+    /// Area normalized triangular filters, see [`MelFilterbank`]. Power and
+    /// dB output filter the power spectrum, magnitude output the magnitudes.
+    Mel {
+        n_mels: usize,
+        fmin: f32,
+        /// Nyquist if `None`.
+        fmax: Option<f32>,
+        scale: MelScale,
+    },
+    /// Constant-Q bins, see [`ConstantQKernel`]. Frames are as long as the
+    /// lowest bin needs, at least `n_fft`, centred where the STFT frames
+    /// would be and not windowed.
+    ConstantQ {
+        fmin: f32,
+        bins_per_octave: usize,
+        n_bins: usize,
+    },
+}
+
+impl FrequencyAxis {
+    /// 128 Slaney mel bands over the whole spectrum.
+    pub fn mel() -> Self {
+        FrequencyAxis::Mel {
+            n_mels: 128,
+            fmin: 0.0,
+            fmax: None,
+            scale: MelScale::Slaney,
+        }
+    }
+
+    /// 7 octaves of semitones from C1.
+    pub fn constant_q() -> Self {
+        FrequencyAxis::ConstantQ {
+            fmin: 32.703,
+            bins_per_octave: 12,
+            n_bins: 84,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct SpectrogramConfig {
+    pub window: WindowFunction,
+    /// FFT size, also the window length.
+    pub n_fft: usize,
+    /// Samples between the starts of two frames.
+    pub hop: usize,
+    /// Pads `n_fft / 2` reflected samples on both ends, so column `i` is
+    /// centred on sample `i * hop`.
+    pub center: bool,
+    pub values: SpectrogramValues,
+    /// dB output is floored this far below its maximum.
+    pub top_db: Option<f32>,
+    #[serde(default)]
+    pub frequency: FrequencyAxis,
+}
+
+impl Default for SpectrogramConfig {
+    fn default() -> Self {
+        Self {
+            window: WindowFunction::Hann,
+            n_fft: 2048,
+            hop: 512,
+            center: true,
+            values: SpectrogramValues::Magnitude,
+            top_db: Some(80.0),
+            frequency: FrequencyAxis::Linear,
+        }
+    }
+}
+
+impl SpectrogramConfig {
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.n_fft > 1 && self.hop > 0,
+            "n_fft must be above 1 and hop non-zero"
+        );
+        Ok(())
+    }
+
+    /// Number of FFT bins, `n_fft / 2 + 1`.
+    pub fn n_bins(&self) -> usize {
+        self.n_fft / 2 + 1
+    }
+
+    /// Number of rows of the output.
+    pub fn n_rows(&self) -> usize {
+        match self.frequency {
+            FrequencyAxis::Linear => self.n_bins(),
+            FrequencyAxis::Mel { n_mels, .. } => n_mels,
+            FrequencyAxis::ConstantQ { n_bins, .. } => n_bins,
+        }
+    }
+
+    /// Number of columns for `len` samples.
+    pub fn n_frames(&self, len: usize) -> usize {
+        if self.center {
+            len / self.hop + 1
+        } else if len == 0 {
+            0
+        } else {
+            len.saturating_sub(self.n_fft) / self.hop + 1
+        }
+    }
+}
+
+enum Projection {
+    Linear,
+    Mel(MelFilterbank),
+    ConstantQ(ConstantQKernel),
+}
+
+/// Planned STFT, reusable across signals of one sample rate.
+pub struct Stft {
+    config: SpectrogramConfig,
+    sample_rate: u32,
+    fft: Arc<dyn Fft<f32>>,
+    /// Samples per frame, `n_fft` except for constant-Q.
+    frame_len: usize,
+    window: Vec<f32>,
+    projection: Projection,
+}
+
+impl Stft {
+    pub fn new(sample_rate: u32, config: SpectrogramConfig) -> Result<Self> {
+        anyhow::ensure!(sample_rate > 0, "sample rate must be non-zero");
+        config.validate()?;
+
+        let projection = match config.frequency {
+            FrequencyAxis::Linear => Projection::Linear,
+            FrequencyAxis::Mel {
+                n_mels,
+                fmin,
+                fmax,
+                scale,
+            } => {
+                anyhow::ensure!(n_mels > 0, "n_mels must be non-zero");
+                Projection::Mel(MelFilterbank::new(
+                    sample_rate,
+                    config.n_fft,
+                    n_mels,
+                    fmin,
+                    fmax,
+                    scale,
+                    true,
+                ))
+            }
+            FrequencyAxis::ConstantQ {
+                fmin,
+                bins_per_octave,
+                n_bins,
+            } => Projection::ConstantQ(ConstantQKernel::new(
+                sample_rate,
+                fmin,
+                bins_per_octave,
+                n_bins,
+                config.n_fft,
+            )?),
+        };
+        let (frame_len, window) = match &projection {
+            Projection::ConstantQ(kernel) => (kernel.fft_len(), vec![1.0; kernel.fft_len()]),
+            _ => (config.n_fft, config.window.coefficients(config.n_fft)),
+        };
+
+        Ok(Self {
+            fft: FftPlanner::new().plan_fft_forward(frame_len),
+            frame_len,
+            window,
+            projection,
+            sample_rate,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &SpectrogramConfig {
+        &self.config
+    }
+
+    pub fn process(&self, samples: &[f32]) -> ZaoSpectrogram {
+        let n_fft = self.config.n_fft;
+        let n_rows = self.config.n_rows();
+        let width = self.config.n_frames(samples.len());
+        let pad = if self.config.center { n_fft / 2 } else { 0 };
+        // Longer constant-Q frames share the centre of the STFT frame.
+        let shift = pad as isize + (self.frame_len / 2) as isize - (n_fft / 2) as isize;
+        let values = self.config.values;
+
+        let mut data = vec![0.0; width * n_rows];
+        let mut buffer = vec![Complex::default(); self.frame_len];
+        let mut scratch = vec![Complex::default(); self.fft.get_inplace_scratch_len()];
+        let mut magnitude = vec![0.0; self.config.n_bins()];
+        let mut column_values = vec![0.0; n_rows];
+        for column in 0..width {
+            let start = (column * self.config.hop) as isize - shift;
+            for (i, c) in buffer.iter_mut().enumerate() {
+                let sample = padded_sample(samples, start + i as isize, self.config.center);
+                *c = Complex::new(sample * self.window[i], 0.0);
+            }
+            self.fft.process_with_scratch(&mut buffer, &mut scratch);
+
+            let power_input = match &self.projection {
+                Projection::Linear => {
+                    for (out, c) in column_values.iter_mut().zip(&buffer) {
+                        *out = c.norm();
+                    }
+                    false
+                }
+                Projection::Mel(filterbank) => {
+                    let power = values != SpectrogramValues::Magnitude;
+                    for (m, c) in magnitude.iter_mut().zip(&buffer) {
+                        *m = if power { c.norm_sqr() } else { c.norm() };
+                    }
+                    filterbank.apply(&magnitude, &mut column_values);
+                    power
+                }
+                Projection::ConstantQ(kernel) => {
+                    kernel.apply(&buffer, &mut column_values);
+                    false
+                }
+            };
+
+            // Row 0 holds the highest bin.
+            for (row, &value) in column_values.iter().enumerate() {
+                data[(n_rows - 1 - row) * width + column] = match (values, power_input) {
+                    (SpectrogramValues::Magnitude, _) | (SpectrogramValues::Power, true) => value,
+                    (SpectrogramValues::Power, false) => value * value,
+                    (SpectrogramValues::Decibels, false) => 20.0 * value.max(AMIN).log10(),
+                    (SpectrogramValues::Decibels, true) => 10.0 * value.max(AMIN * AMIN).log10(),
+                };
+            }
+        }
+
+        if self.config.values == SpectrogramValues::Decibels
+            && let Some(top_db) = self.config.top_db
+        {
+            let floor = data.iter().copied().fold(f32::NEG_INFINITY, f32::max) - top_db;
+            data.iter_mut().for_each(|v| *v = v.max(floor));
+        }
+
+        let scale = match self.projection {
+            Projection::Linear => SpectrogramScale::Linear,
+            Projection::Mel(_) => SpectrogramScale::Mel,
+            Projection::ConstantQ(_) => SpectrogramScale::ConstantQ,
+        };
+        let mut spectrogram = ZaoSpectrogram::new(
+            data,
+            width,
+            n_rows,
+            scale,
+            self.sample_rate,
+            self.config.hop as f64,
+        )
+        .expect("data is sized width x n_rows");
+        spectrogram.values = values;
+        spectrogram.n_fft = self.frame_len;
+        spectrogram.config = Some(self.config.clone());
+        spectrogram
+    }
+}
+
+/// Spectrogram of mono `samples`, see [`Stft`].
+pub fn compute_stft(
+    samples: &[f32],
+    sample_rate: u32,
+    config: &SpectrogramConfig,
+) -> Result<ZaoSpectrogram> {
+    Ok(Stft::new(sample_rate, config.clone())?.process(samples))
+}
+
+/// Sample `index` of the signal, reflected at the ends when `reflect` is
+/// set and zero outside otherwise.
+fn padded_sample(samples: &[f32], index: isize, reflect: bool) -> f32 {
+    let len = samples.len() as isize;
+    if (0..len).contains(&index) {
+        return samples[index as usize];
+    }
+    if !reflect || len < 2 {
+        return 0.0;
+    }
+    let period = 2 * (len - 1);
+    let i = index.rem_euclid(period);
+    samples[(if i < len { i } else { period - i }) as usize]
+}
+
+/// Modified Bessel function of the first kind, order 0.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_sq = x * x / 4.0;
+    for k in 1..50 {
+        term *= half_sq / (k * k) as f64;
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
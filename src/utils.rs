@@ -1,15 +1,48 @@
 use crate::{
     chapters::extract_chapters,
     file::{EntryKind, list_dir},
+    progress::{Amount, JobControl, Progress, Stage},
 };
 use anyhow::{Context, Result};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::{
     fs::{self},
     io::Read,
     path::{Path, PathBuf},
+    sync::LazyLock,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
+/// Series name parsed from a release file name, lowercased, e.g. `frieren`
+/// for `[Group] Frieren - 03 (1080p) [ABCD1234].mkv`. Tags in brackets are
+/// dropped and the name ends before the episode number. `None` when no
+/// episode number is found.
+pub fn series_name(path: impl AsRef<Path>) -> Option<String> {
+    static TAGS: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\[[^\]]*\]|\([^)]*\)|\{[^}]*\}").unwrap());
+    // `Name - 03` first, so numbers inside titles like `Mob Psycho 100 - 03` are kept.
+    static EPISODE: LazyLock<[Regex; 2]> = LazyLock::new(|| {
+        let number = r"(?:s\d{1,2}\s*)?(?:e|ep|episode\s*)?\d{1,4}(?:v\d)?(?:\s|$)";
+        [
+            Regex::new(&format!(r"(?i)^(.*?)\s+-\s+{number}")).unwrap(),
+            Regex::new(&format!(r"(?i)^(.*?)\s+{number}")).unwrap(),
+        ]
+    });
+
+    let stem = path.as_ref().file_stem()?.to_str()?;
+    let stem = TAGS.replace_all(stem, " ").replace(['_', '.'], " ");
+    let stem = stem.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let name = EPISODE
+        .iter()
+        .find_map(|re| re.captures(&stem))?
+        .get(1)?
+        .as_str();
+    let name = name.trim_end_matches([' ', '-']).to_lowercase();
+    (!name.is_empty()).then_some(name)
+}
+
 pub(crate) fn get_third_party_binary(name: &str) -> PathBuf {
     // CARGO_MANIFEST_DIR will be the zaohelper/ path even when used from zaoai
     let base = Path::new(env!("CARGO_MANIFEST_DIR"));
@@ -40,31 +73,59 @@ impl ListDirSplit {
 pub fn list_dir_with_kind_has_chapters_split(
     list: &[EntryKind],
     cull_empty_folders: bool,
+) -> Result<ListDirSplit> {
+    list_dir_with_kind_has_chapters_split_with_control(
+        list,
+        cull_empty_folders,
+        &JobControl::default(),
+    )
+}
+
+/// [`list_dir_with_kind_has_chapters_split`] reporting every scanned file to
+/// `control`. Returns [`crate::progress::Cancelled`] once cancelled.
+pub fn list_dir_with_kind_has_chapters_split_with_control(
+    list: &[EntryKind],
+    cull_empty_folders: bool,
+    control: &JobControl,
 ) -> Result<ListDirSplit> {
     // MULTIHREADED VERSION
-    return list_dir_with_kind_has_chapters_split_multithread(list, cull_empty_folders);
+    return list_dir_with_kind_has_chapters_split_multithread_with_control(
+        list,
+        cull_empty_folders,
+        control,
+    );
 
     #[allow(unreachable_code)]
     let mut list_dir_split = ListDirSplit::default();
 
     for item in list {
+        control.check()?;
         match item {
             EntryKind::File(path_buf) => {
                 let mut has_chapters = false;
-                if let Some(ext) = path_buf.extension() {
-                    if ext == "mkv" {
-                        let mkv_file_str = path_buf
-                            .to_str()
-                            .ok_or_else(|| anyhow::anyhow!("Invalid temp path string"))?;
-
-                        // Read chapters from local copy
-                        match extract_chapters(&mkv_file_str) {
-                            Ok(chapters) => has_chapters = !chapters.iter().next().is_none(),
-                            Err(e) => println!("{e}"),
-                        }
+                if path_buf.extension().is_some_and(|ext| ext == "mkv") {
+                    let mkv_file_str = path_buf
+                        .to_str()
+                        .ok_or_else(|| anyhow::anyhow!("Invalid temp path string"))?;
+
+                    // Read chapters from local copy
+                    match extract_chapters(mkv_file_str) {
+                        Ok(chapters) => has_chapters = chapters.iter().next().is_some(),
+                        Err(e) => println!("{e}"),
                     }
                 }
 
+                control.report(Progress {
+                    stage: Stage::Scanning,
+                    file: Some(path_buf),
+                    processed: Amount::Items(
+                        (list_dir_split.with_chapters.len()
+                            + list_dir_split.without_chapters.len()
+                            + 1) as u64,
+                    ),
+                    total: None,
+                });
+
                 if has_chapters {
                     list_dir_split.with_chapters.push(item.clone());
                 } else {
@@ -74,8 +135,11 @@ pub fn list_dir_with_kind_has_chapters_split(
             EntryKind::Directory(path_buf) => {
                 let path_buf_entry_list = list_dir(path_buf, cull_empty_folders)
                     .with_context(|| format!("Failed to copy file: {}", path_buf.display()))?;
-                let dir_res =
-                    list_dir_with_kind_has_chapters_split(&path_buf_entry_list, cull_empty_folders);
+                let dir_res = list_dir_with_kind_has_chapters_split_with_control(
+                    &path_buf_entry_list,
+                    cull_empty_folders,
+                    control,
+                );
                 match dir_res {
                     Ok(dir_split) => {
                         #[allow(unused_variables)]
@@ -110,6 +174,33 @@ pub fn list_dir_with_kind_has_chapters_split(
 pub fn list_dir_with_kind_has_chapters_split_multithread(
     list: &[EntryKind],
     cull_empty_folders: bool,
+) -> Result<ListDirSplit> {
+    list_dir_with_kind_has_chapters_split_multithread_with_control(
+        list,
+        cull_empty_folders,
+        &JobControl::default(),
+    )
+}
+
+/// [`list_dir_with_kind_has_chapters_split_multithread`] reporting every
+/// scanned file to `control`. The total is unknown as directories are
+/// listed while scanning. Returns [`crate::progress::Cancelled`] once cancelled.
+pub fn list_dir_with_kind_has_chapters_split_multithread_with_control(
+    list: &[EntryKind],
+    cull_empty_folders: bool,
+    control: &JobControl,
+) -> Result<ListDirSplit> {
+    let scanned = AtomicU64::new(0);
+    let split = split_has_chapters(list, cull_empty_folders, control, &scanned)?;
+    control.check()?;
+    Ok(split)
+}
+
+fn split_has_chapters(
+    list: &[EntryKind],
+    cull_empty_folders: bool,
+    control: &JobControl,
+    scanned: &AtomicU64,
 ) -> Result<ListDirSplit> {
     let mut split = ListDirSplit::default();
 
@@ -118,18 +209,29 @@ pub fn list_dir_with_kind_has_chapters_split_multithread(
 
         for item in list.iter().cloned() {
             let handle = scope.spawn(move || -> Result<ListDirSplit> {
+                if control.is_cancelled() {
+                    return Ok(ListDirSplit::default());
+                }
+
                 match &item {
                     EntryKind::File(path_buf) => {
                         let mut has_chapters = false;
-                        if path_buf.extension().map_or(false, |ext| ext == "mkv") {
-                            if let Some(mkv_file_str) = path_buf.to_str() {
-                                match extract_chapters(mkv_file_str) {
-                                    Ok(chapters) => has_chapters = chapters.iter().next().is_some(),
-                                    Err(e) => eprintln!("Chapter extract failed: {e}"),
-                                }
+                        if path_buf.extension().is_some_and(|ext| ext == "mkv")
+                            && let Some(mkv_file_str) = path_buf.to_str()
+                        {
+                            match extract_chapters(mkv_file_str) {
+                                Ok(chapters) => has_chapters = chapters.iter().next().is_some(),
+                                Err(e) => eprintln!("Chapter extract failed: {e}"),
                             }
                         }
 
+                        control.report(Progress {
+                            stage: Stage::Scanning,
+                            file: Some(path_buf),
+                            processed: Amount::Items(scanned.fetch_add(1, Ordering::Relaxed) + 1),
+                            total: None,
+                        });
+
                         let mut s = ListDirSplit::default();
                         if has_chapters {
                             s.with_chapters.push(item);
@@ -146,10 +248,7 @@ pub fn list_dir_with_kind_has_chapters_split_multithread(
                             })?;
 
                         // Recursive call — scoped
-                        list_dir_with_kind_has_chapters_split_multithread(
-                            &entries,
-                            cull_empty_folders,
-                        )
+                        split_has_chapters(&entries, cull_empty_folders, control, scanned)
                     }
 
                     EntryKind::Other(_) => Ok(ListDirSplit::default()),